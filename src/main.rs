@@ -1,14 +1,17 @@
 // extern crate serde_json;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fs::{self, File};
-use std::io::{BufReader, Write};
+use std::io::{self, BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::LazyLock;
 use std::time::SystemTime;
 
 
@@ -35,17 +38,41 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Parse tree JSON and output TSV format (path, size, timestamp)
+    /// Parse tree JSON and output a listing (TSV, CSV, JSON Lines or SQLite)
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
     Tree {
-        /// Path to the tree JSON file (generated with: tree -J --du -D --timefmt "%Y-%m-%d")
-        #[arg(value_name = "FILE")]
-        file: PathBuf,
+        #[command(subcommand)]
+        action: Option<TreeCommands>,
+
+        /// Path to the tree JSON file (generated with: tree -J --du -D --timefmt "%Y-%m-%d"),
+        /// or a directory to scan directly
+        #[arg(value_name = "FILE", required = true)]
+        file: Option<PathBuf>,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = TreeFormat::Tsv)]
+        format: TreeFormat,
+
+        /// Write output to this file instead of stdout (required for sqlite)
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<PathBuf>,
+
+        /// Print the report summary (directories, files, total size) to stderr
+        #[arg(long)]
+        summary: bool,
+
+        #[command(flatten)]
+        filter: Box<TreeFilter>,
     },
     /// Generate and sync Netscape-style bookmark index files for folders
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
     Bookmarks {
+        #[command(subcommand)]
+        action: Option<BookmarksCommands>,
+
         /// Path to the folder to generate bookmarks for
-        #[arg(value_name = "FOLDER")]
-        folder: PathBuf,
+        #[arg(value_name = "FOLDER", required = true)]
+        folder: Option<PathBuf>,
 
         /// Name of the index file (default: index.html)
         #[arg(short, long, default_value = "index.html")]
@@ -54,6 +81,54 @@ enum Commands {
         /// Include subdirectories recursively
         #[arg(short, long)]
         recursive: bool,
+
+        /// Limit recursion to this many levels of subfolders
+        #[arg(long, value_name = "N", requires = "recursive")]
+        max_depth: Option<usize>,
+
+        /// Only index files matching these globs (e.g. '*.JPG,*.RAF')
+        #[arg(long, value_name = "GLOB", value_delimiter = ',')]
+        include: Vec<glob::Pattern>,
+
+        /// Skip files and folders matching these globs. Each folder's
+        /// .sbtignore (gitignore syntax) is honored as well
+        #[arg(long, value_name = "GLOB", value_delimiter = ',')]
+        exclude: Vec<glob::Pattern>,
+
+        /// What to do with entries whose file no longer exists
+        #[arg(long, value_enum, default_value = "keep")]
+        sync: SyncPolicy,
+
+        /// Sort entries (default: keep the existing order, append new files)
+        #[arg(long, value_enum)]
+        sort: Option<BookmarkSort>,
+
+        /// With --sort, keep the existing order and only insert new entries
+        /// at their sorted position
+        #[arg(long, requires = "sort")]
+        preserve_order: bool,
+
+        /// Caption new images from their XMP/IPTC/EXIF description and date
+        /// them by EXIF capture time
+        #[arg(long)]
+        image_metadata: bool,
+
+        /// Keep running and re-sync whenever files are added, removed or renamed
+        #[arg(short, long, conflicts_with = "dry_run")]
+        watch: bool,
+
+        /// Report the entries that would be added, removed or changed
+        /// without writing the index
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Print a unified diff of the index file's changes
+        #[arg(long)]
+        diff: bool,
+
+        /// Number of rotating backups of the index to keep (index.html.bak.1 is newest)
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_BOOKMARK_BACKUPS)]
+        backups: usize,
     },
     /// Process photo albums: copy bookmarked files and generate resized versions
     Pixie {
@@ -70,11 +145,310 @@ enum Commands {
         /// Output directory for processed files
         #[arg(short, long, value_name = "OUTPUT_DIR")]
         output_dir: PathBuf,
+
+        /// Only publish notes with one of these tags (comma-separated)
+        #[arg(long, value_name = "TAGS", value_delimiter = ',')]
+        include_tags: Vec<String>,
+
+        /// Never publish notes with these tags (comma-separated)
+        #[arg(long, value_name = "TAGS", value_delimiter = ',', default_values_t = default_exclude_tags())]
+        exclude_tags: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum TreeCommands {
+    /// Compare two tree JSON snapshots and report what changed
+    Diff {
+        /// The older snapshot (tree JSON file or directory)
+        #[arg(value_name = "OLD")]
+        old: PathBuf,
+
+        /// The newer snapshot (tree JSON file or directory)
+        #[arg(value_name = "NEW")]
+        new: PathBuf,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = DiffFormat::Text)]
+        format: DiffFormat,
+    },
+    /// Scan a directory and write tree -J compatible JSON (no external tree binary needed)
+    Scan {
+        /// Directory to scan
+        #[arg(value_name = "PATH")]
+        path: PathBuf,
+
+        /// Write JSON to this file instead of stdout
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<PathBuf>,
+
+        /// Time format for the "time" field (same as tree --timefmt)
+        #[arg(long, default_value = DEFAULT_TREE_TIMEFMT)]
+        timefmt: String,
+
+        /// Include hidden files (same as tree -a)
+        #[arg(short, long)]
+        all: bool,
+    },
+    /// Disk-usage report: largest directories and files, sizes by extension and year
+    Du {
+        /// Path to the tree JSON file (generated with --du), or a directory to scan
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Number of entries to show in each section
+        #[arg(short = 'n', long, default_value_t = 20)]
+        top: usize,
+    },
+    /// Find likely duplicate files (same name and size) across one or more snapshots
+    Dupes {
+        /// Tree JSON files or directories to scan
+        #[arg(value_name = "FILE", required = true)]
+        files: Vec<PathBuf>,
+
+        /// Confirm duplicates by hashing file contents when the paths exist locally
+        #[arg(long)]
+        hash: bool,
+
+        /// Ignore files smaller than this, e.g. 1M
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "1")]
+        min_size: u64,
+    },
+    /// Generate a self-contained HTML report with a collapsible tree and a treemap
+    Html {
+        /// Path to the tree JSON file, or a directory to scan
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Write the report to this file instead of stdout
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<PathBuf>,
+    },
+    /// Convert a listing (or a subtree of it) into a Netscape bookmark file
+    Bookmarks {
+        /// Path to the tree JSON file, or a directory to scan
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Only convert this directory, relative to the root (e.g. Archive/2019)
+        #[arg(short, long, value_name = "PATH")]
+        subtree: Option<String>,
+
+        /// Write the bookmarks to this file instead of stdout
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum BookmarksCommands {
+    /// Convert a bookmark index to JSON, Markdown, OPML or CSV
+    Export {
+        /// Bookmark file, or a folder containing index.html
+        #[arg(value_name = "INDEX")]
+        index: PathBuf,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+
+        /// Write the export to this file instead of stdout
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<PathBuf>,
+    },
+    /// Merge a browser export (Netscape HTML or Firefox JSON backup) into an index
+    Import {
+        /// The browser's bookmark export
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Bookmark file to merge into, or a folder containing index.html
+        #[arg(long, value_name = "INDEX")]
+        into: PathBuf,
+
+        /// Number of rotating backups of the index to keep (INDEX.bak.1 is newest)
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_BOOKMARK_BACKUPS)]
+        backups: usize,
+    },
+    /// Roll an index back to one of its backups
+    Restore {
+        /// Bookmark file, or a folder containing index.html
+        #[arg(value_name = "INDEX")]
+        index: PathBuf,
+
+        /// Which backup to restore (1 is the most recent)
+        #[arg(short, long, value_name = "N", default_value_t = 1)]
+        backup: usize,
+
+        /// List the available backups instead of restoring
+        #[arg(short, long)]
+        list: bool,
+
+        /// Number of rotating backups of the index to keep (INDEX.bak.1 is newest)
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_BOOKMARK_BACKUPS)]
+        backups: usize,
+    },
+    /// Show or set an entry's caption, or caption an index interactively
+    Caption {
+        /// Bookmark file, or a folder containing index.html
+        #[arg(value_name = "INDEX")]
+        index: PathBuf,
+
+        /// The entry, by file name or href (omit to go through every entry)
+        #[arg(value_name = "FILE")]
+        file: Option<String>,
+
+        /// The new caption; an empty string removes it. Omit to print the current one
+        #[arg(value_name = "TEXT")]
+        text: Option<String>,
+
+        /// In interactive mode, skip entries that already have a caption
+        #[arg(long, conflicts_with = "file")]
+        missing: bool,
+
+        /// Number of rotating backups of the index to keep
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_BOOKMARK_BACKUPS)]
+        backups: usize,
+    },
+    /// Add or remove tags on entries, or print their tags
+    Tag {
+        /// Bookmark file, or a folder containing index.html
+        #[arg(value_name = "INDEX")]
+        index: PathBuf,
+
+        /// Entries by file name or href
+        #[arg(value_name = "FILE", required = true)]
+        files: Vec<String>,
+
+        /// Tags to add (e.g. favorite,portfolio)
+        #[arg(short, long, value_name = "TAGS", value_delimiter = ',')]
+        add: Vec<String>,
+
+        /// Tags to remove
+        #[arg(short, long, value_name = "TAGS", value_delimiter = ',')]
+        remove: Vec<String>,
+
+        /// Number of rotating backups of the index to keep
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_BOOKMARK_BACKUPS)]
+        backups: usize,
     },
+    /// List the entries carrying all the given tags
+    Find {
+        /// Bookmark file, or a folder containing index.html
+        #[arg(value_name = "INDEX")]
+        index: PathBuf,
+
+        /// Tag to look for; repeat to require several
+        #[arg(short, long, value_name = "TAG", required = true)]
+        tag: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ExportFormat {
+    /// The folder tree as a JSON document
+    Json,
+    /// A nested Markdown list of links
+    Markdown,
+    /// OPML outline
+    Opml,
+    /// One row per link, with its folder path
+    Csv,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DiffFormat {
+    /// Human-readable report
+    Text,
+    /// A single JSON document
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TreeFormat {
+    /// Tab-separated path, size, time
+    Tsv,
+    /// Comma-separated with a header row
+    Csv,
+    /// One JSON object per node
+    Jsonl,
+    /// SQLite database with a `nodes` table
+    Sqlite,
+}
+
+// What to do with bookmarks whose file is gone from disk
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum SyncPolicy {
+    /// Leave them in the index
+    #[default]
+    Keep,
+    /// Keep them, tagged "missing"
+    MarkMissing,
+    /// Drop them from the index
+    Remove,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BookmarkSort {
+    /// By name
+    Name,
+    /// By name, comparing runs of digits as numbers (IMG_2 before IMG_10)
+    Natural,
+    /// By modification time
+    Mtime,
+    /// By EXIF capture date, falling back to modification time
+    Exif,
+    /// By file size
+    Size,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TreeNodeKind {
+    File,
+    Directory,
+    Link,
+}
+
+// Filters for tree listings. A directory that doesn't match is still
+// descended into, so `--type file --glob '*/Archive/*'` finds files below it
+#[derive(Args, Clone, Default)]
+struct TreeFilter {
+    /// Only include paths matching this glob (e.g. '*/Archive/*.jpg')
+    #[arg(long, value_name = "PATTERN")]
+    glob: Option<glob::Pattern>,
+
+    /// Only include paths matching this regular expression
+    #[arg(long, value_name = "REGEX")]
+    regex: Option<Regex>,
+
+    /// Minimum size, e.g. 500K, 1G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Maximum size, e.g. 500K, 1G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Only include entries with a time on or after this date (YYYY, YYYY-MM or YYYY-MM-DD)
+    #[arg(long, value_name = "DATE", value_parser = parse_filter_date)]
+    after: Option<chrono::NaiveDate>,
+
+    /// Only include entries with a time before this date (YYYY, YYYY-MM or YYYY-MM-DD)
+    #[arg(long, value_name = "DATE", value_parser = parse_filter_date)]
+    before: Option<chrono::NaiveDate>,
+
+    /// Only include this type of entry
+    #[arg(long = "type", value_enum, value_name = "TYPE")]
+    node_type: Option<TreeNodeKind>,
+
+    /// Don't descend below this depth (the root is depth 0)
+    #[arg(long, value_name = "DEPTH")]
+    max_depth: Option<usize>,
 }
 
 // Data Structures
 
+#[cfg(test)]
 #[derive(Serialize, Deserialize)]
 struct Person {
     name: String,
@@ -82,28 +456,98 @@ struct Person {
     phones: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+// tree -J writes one node per root argument, followed by the report
+// unless --noreport was given
+#[derive(Deserialize)]
+#[serde(from = "Vec<TreeListItem>")]
 struct TreeList {
-    node: TreeNode,
-    report: ReportNode,
+    roots: Vec<TreeNode>,
+    report: Option<ReportNode>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TreeListItem {
+    Report(ReportNode),
+    Node(Box<TreeNode>),
+}
+
+impl From<Vec<TreeListItem>> for TreeList {
+    fn from(items: Vec<TreeListItem>) -> Self {
+        let mut tree = TreeList { roots: Vec::new(), report: None };
+        for item in items {
+            match item {
+                TreeListItem::Report(report) => tree.report = Some(report),
+                TreeListItem::Node(node) => tree.roots.push(*node),
+            }
+        }
+        tree
+    }
+}
+
+impl Serialize for TreeList {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        let mut seq = serializer.serialize_seq(None)?;
+        for root in &self.roots {
+            seq.serialize_element(root)?;
+        }
+        if let Some(report) = &self.report {
+            seq.serialize_element(report)?;
+        }
+        seq.end()
+    }
+}
+
+// Every field except the type is optional, since which ones tree emits depends
+// on its flags: -s/--du (size), -D (time), -p (mode, prot), -u (user),
+// -g (group), --inodes (inode), --device (dev). Links carry a target, and
+// unreadable entries are reported as {"error": "..."} without a type or name.
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct TreeNode {
     // rename "type" to node_type because type is a reserved keyword
-    #[serde(rename = "type")]
+    #[serde(rename = "type", default = "default_tree_node_type", skip_serializing_if = "is_error_node_type")]
     node_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     name: String,
-    size: u64,
-    time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inode: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dev: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prot: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     contents: Option<Vec<TreeNode>>,
 }
 
-#[derive(Serialize, Deserialize)]
+fn default_tree_node_type() -> String {
+    "error".to_string()
+}
+
+fn is_error_node_type(node_type: &str) -> bool {
+    node_type == "error"
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 struct ReportNode {
     #[serde(rename = "type")]
     node_type: String,
-    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
     directories: u64,
     files: u64,
 }
@@ -120,6 +564,14 @@ struct PixieConfig {
     resize_args: HashMap<String, String>,  // e.g. {"rs": "800x800>", "thumb": "200x200"}
     #[serde(default)]
     index_transform: String,  // unused for now
+    #[serde(default)]
+    include_tags: Vec<String>,  // only publish entries with one of these tags; empty means all
+    #[serde(default = "default_exclude_tags")]
+    exclude_tags: Vec<String>,  // never publish entries with these tags
+}
+
+fn default_exclude_tags() -> Vec<String> {
+    vec!["private".to_string()]
 }
 
 #[derive(Debug)]
@@ -144,6 +596,8 @@ struct PhotoInfo {
     w: Option<u32>,
     h: Option<u32>,
     caption: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
     sizes: HashMap<String, SizeInfo>,
 }
 
@@ -157,8 +611,10 @@ struct SizeInfo {
 #[derive(Debug, Clone)]
 struct BookmarkFile {
     href: String,
+    #[allow(dead_code)]
     name: String,
     caption: Option<String>,
+    tags: Vec<String>,
 }
 
 #[test]
@@ -211,13 +667,52 @@ fn test_typed_example() {
    let tree: TreeList = serde_json::from_str(data).expect("JSON was not well-formatted");
 
    // https://web.mit.edu/rust-lang_v1.25/arch/amd64_ubuntu1404/share/doc/rust/html/book/first-edition/testing.html
-   assert_eq!(tree.node.name, "/Users/ojas/GDrive");
-   assert_eq!(tree.report.files, 29484);
+   assert_eq!(tree.roots[0].name, "/Users/ojas/GDrive");
+   assert_eq!(tree.report.unwrap().files, 29484);
 
   // let nodes: Vec<Node> = serde_json::from_str(data).expect("JSON was not well-formatted");
 //   println!("{:#?}", tree.report.node_type);
 }
 
+#[test]
+fn test_tree_options_example() {
+    // tree -J -p -u -g --inodes --noreport /Users/ojas/GDrive /Volumes/Photos
+    // (no --du or -D, so no sizes or times, and no report)
+    let data = r#"
+    [
+      {"type":"directory","name":"/Users/ojas/GDrive","inode":1234,"mode":"0755","prot":"drwxr-xr-x","user":"ojas","group":"staff","contents":[
+        {"type":"link","name":"Current","target":"Archive/2023","inode":1235,"mode":"0777","prot":"lrwxrwxrwx","user":"ojas","group":"staff"},
+        {"type":"directory","name":"Private","inode":1236,"mode":"0700","prot":"drwx------","user":"root","group":"wheel","contents":[
+          {"error": "opening dir"}
+        ]}
+      ]},
+      {"type":"directory","name":"/Volumes/Photos","contents":[
+        {"type":"file","name":"XÚÖ.jpg"}
+      ]}
+    ]"#;
+
+    let tree: TreeList = serde_json::from_str(data).expect("JSON was not well-formatted");
+
+    assert_eq!(tree.roots.len(), 2);
+    assert!(tree.report.is_none());
+    let contents = tree.roots[0].contents.as_ref().unwrap();
+    assert_eq!(contents[0].target.as_deref(), Some("Archive/2023"));
+    assert_eq!(contents[0].size, None);
+    assert_eq!(contents[1].user.as_deref(), Some("root"));
+    assert_eq!(contents[1].prot.as_deref(), Some("drwx------"));
+    let error = &contents[1].contents.as_ref().unwrap()[0];
+    assert_eq!(error.node_type, "error");
+    assert_eq!(error.error.as_deref(), Some("opening dir"));
+    assert_eq!(tree.roots[1].contents.as_ref().unwrap()[0].name, "XÚÖ.jpg");
+
+    // Writing it back out matches the tree -J shape
+    let json = serde_json::to_value(&tree).unwrap();
+    assert_eq!(json[0]["contents"][1]["contents"][0], serde_json::json!({"error": "opening dir"}));
+    assert_eq!(json.as_array().unwrap().len(), 2);
+}
+
+const DEFAULT_TREE_TIMEFMT: &str = "%Y-%m-%d";
+
 fn read_tree_from_file<P: AsRef<Path>>(path: P) -> Result<TreeList,Box<dyn Error>> {
     // Open the file in read-only mode with buffer.
     let file = File::open(path)?;
@@ -230,6 +725,149 @@ fn read_tree_from_file<P: AsRef<Path>>(path: P) -> Result<TreeList,Box<dyn Error
     Ok(tree)
 }
 
+// Accept either a tree JSON file or a directory, which is scanned natively
+fn load_tree(path: &Path) -> Result<TreeList, Box<dyn Error>> {
+    if path.is_dir() {
+        scan_tree(path, DEFAULT_TREE_TIMEFMT, false)
+    } else {
+        read_tree_from_file(path)
+    }
+}
+
+fn format_file_time(time: SystemTime, timefmt: &str) -> String {
+    chrono::DateTime::<chrono::Local>::from(time)
+        .format(timefmt)
+        .to_string()
+}
+
+// chrono panics while formatting with an unknown specifier, so reject those
+// before scanning
+fn check_timefmt(timefmt: &str) -> Result<(), Box<dyn Error>> {
+    use chrono::format::{Item, StrftimeItems};
+    if StrftimeItems::new(timefmt).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("Invalid --timefmt: {}", timefmt).into());
+    }
+    Ok(())
+}
+
+// Unreadable entries become error nodes, like tree does, instead of ending the scan
+fn error_tree_node(name: String, error: impl ToString) -> TreeNode {
+    TreeNode {
+        node_type: "error".to_string(),
+        name,
+        error: Some(error.to_string()),
+        ..Default::default()
+    }
+}
+
+fn scan_tree_node(
+    path: &Path,
+    name: String,
+    timefmt: &str,
+    all: bool,
+    report: &mut ReportNode,
+) -> TreeNode {
+    // Don't follow symlinks, like tree without -l
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => return error_tree_node(name, e),
+    };
+    let time = metadata.modified().ok().map(|t| format_file_time(t, timefmt));
+
+    if metadata.file_type().is_symlink() {
+        report.files += 1;
+        return TreeNode {
+            node_type: "link".to_string(),
+            name,
+            target: fs::read_link(path).ok().map(|t| t.to_string_lossy().to_string()),
+            size: Some(metadata.len()),
+            time,
+            ..Default::default()
+        };
+    }
+
+    if !metadata.is_dir() {
+        report.files += 1;
+        return TreeNode {
+            node_type: "file".to_string(),
+            name,
+            size: Some(metadata.len()),
+            time,
+            ..Default::default()
+        };
+    }
+
+    let mut entries = Vec::new();
+    let mut unreadable = Vec::new();
+    let mut error = None;
+    match fs::read_dir(path) {
+        Ok(dir_entries) => {
+            for entry in dir_entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        unreadable.push(error_tree_node(String::new(), e));
+                        continue;
+                    }
+                };
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if !all && file_name.starts_with('.') {
+                    continue;
+                }
+                entries.push((entry.path(), file_name));
+            }
+        }
+        Err(e) => error = Some(e.to_string()),
+    }
+    entries.sort_by(|a, b| a.1.cmp(&b.1));
+
+    // du-style size: the directory itself plus everything below it
+    let mut size = metadata.len();
+    let mut contents = Vec::new();
+    for (child_path, child_name) in entries {
+        let child = scan_tree_node(&child_path, child_name, timefmt, all, report);
+        if child.node_type == "directory" {
+            report.directories += 1;
+        }
+        size += child.size.unwrap_or(0);
+        contents.push(child);
+    }
+    contents.extend(unreadable);
+
+    TreeNode {
+        node_type: "directory".to_string(),
+        name,
+        size: Some(size),
+        time,
+        error,
+        contents: Some(contents),
+        ..Default::default()
+    }
+}
+
+fn scan_tree(path: &Path, timefmt: &str, all: bool) -> Result<TreeList, Box<dyn Error>> {
+    check_timefmt(timefmt)?;
+    let mut report = ReportNode {
+        node_type: "report".to_string(),
+        size: None,
+        directories: 0,
+        files: 0,
+    };
+    let node = scan_tree_node(path, path.to_string_lossy().to_string(), timefmt, all, &mut report);
+    if node.node_type == "error" {
+        return Err(format!("Cannot scan {}: {}", path.display(), node.error.unwrap_or_default()).into());
+    }
+    report.size = node.size;
+
+    Ok(TreeList { roots: vec![node], report: Some(report) })
+}
+
+fn write_tree_json<W: Write>(tree: &TreeList, writer: W) -> Result<(), Box<dyn Error>> {
+    serde_json::to_writer(writer, tree)?;
+    Ok(())
+}
+
+#[allow(dead_code)]
 fn walk_tree(node: &TreeNode, indent: usize) {
     println!("{}{}", " ".repeat(indent), node.name);
     if let Some(contents) = &node.contents {
@@ -239,460 +877,4559 @@ fn walk_tree(node: &TreeNode, indent: usize) {
     }
 }
 
-fn walk_tree_fullpath(node: &TreeNode, prefix: &str, indent: usize) {
-    let _ = indent; // suppress unused variable warning
-    let full_path = format!("{}{}", prefix, &node.name);
-    println!("{}\t{}\t{}", full_path, node.size, node.time);
-    if let Some(contents) = &node.contents {
-        for child in contents {
-            walk_tree_fullpath(child, &format!("{}/", full_path), indent + 2);
+// One flattened line of a tree listing, shared by all output formats
+#[derive(Serialize, Debug, PartialEq)]
+struct TreeRow {
+    path: String,
+    #[serde(rename = "type")]
+    node_type: String,
+    size: Option<u64>,
+    time: Option<String>,
+    depth: usize,
+    target: Option<String>,
+    inode: Option<u64>,
+    dev: Option<u64>,
+    mode: Option<String>,
+    prot: Option<String>,
+    user: Option<String>,
+    group: Option<String>,
+    error: Option<String>,
+}
+
+impl TreeRow {
+    fn from_node(node: &TreeNode, path: String, depth: usize) -> TreeRow {
+        TreeRow {
+            path,
+            node_type: node.node_type.clone(),
+            size: node.size,
+            time: node.time.clone(),
+            depth,
+            target: node.target.clone(),
+            inode: node.inode,
+            dev: node.dev,
+            mode: node.mode.clone(),
+            prot: node.prot.clone(),
+            user: node.user.clone(),
+            group: node.group.clone(),
+            error: node.error.clone(),
         }
     }
 }
 
-fn handle_tree_command(path: &PathBuf) -> Result<(), Box<dyn Error>> {
-    let tree: TreeList = read_tree_from_file(path)?;
-    //walk_tree(&tree.node, 0);
-    walk_tree_fullpath(&tree.node, "", 0);
+enum TreeOutput {
+    Tsv(Box<dyn Write>),
+    Csv(Box<csv::Writer<Box<dyn Write>>>),
+    Jsonl(Box<dyn Write>),
+    Sqlite(rusqlite::Connection),
+}
 
-    println!("{:#?}", tree.report.files);
+impl TreeOutput {
+    fn open(format: TreeFormat, output: Option<&Path>) -> Result<TreeOutput, Box<dyn Error>> {
+        if format == TreeFormat::Sqlite {
+            let db_path = output.ok_or("The sqlite format requires --output <FILE>")?;
+            let conn = rusqlite::Connection::open(db_path)?;
+            // Replace the table inside the transaction, so a failed run
+            // leaves the previous data in place
+            conn.execute_batch(
+                "BEGIN;
+                 DROP TABLE IF EXISTS nodes;
+                 CREATE TABLE nodes (
+                     id INTEGER PRIMARY KEY,
+                     path TEXT NOT NULL,
+                     type TEXT NOT NULL,
+                     size INTEGER,
+                     time TEXT,
+                     depth INTEGER NOT NULL,
+                     target TEXT,
+                     inode INTEGER,
+                     dev INTEGER,
+                     mode TEXT,
+                     prot TEXT,
+                     user TEXT,
+                     \"group\" TEXT,
+                     error TEXT
+                 );",
+            )?;
+            return Ok(TreeOutput::Sqlite(conn));
+        }
 
-//    let data = fs::read_to_string(filename).expect("Unable to read file");
-    // let json_value: Value = serde_json::from_str(&data).expect("JSON was not well-formatted");
-    // println!("{:#}", json_value);
+        let writer: Box<dyn Write> = match output {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(io::stdout().lock())),
+        };
 
-    // dump_tree(&json_value[0], 0);
-    Ok(())
-}
+        Ok(match format {
+            TreeFormat::Tsv => TreeOutput::Tsv(writer),
+            TreeFormat::Csv => TreeOutput::Csv(Box::new(csv::Writer::from_writer(writer))),
+            TreeFormat::Jsonl => TreeOutput::Jsonl(writer),
+            TreeFormat::Sqlite => unreachable!(),
+        })
+    }
 
-// Bookmarks functionality
+    fn write_row(&mut self, row: &TreeRow) -> Result<(), Box<dyn Error>> {
+        match self {
+            TreeOutput::Tsv(w) => writeln!(
+                w,
+                "{}\t{}\t{}",
+                row.path,
+                row.size.map(|size| size.to_string()).unwrap_or_default(),
+                row.time.as_deref().unwrap_or("")
+            )?,
+            TreeOutput::Csv(w) => w.serialize(row)?,
+            TreeOutput::Jsonl(w) => {
+                serde_json::to_writer(&mut *w, row)?;
+                writeln!(w)?;
+            }
+            TreeOutput::Sqlite(conn) => {
+                let mut stmt = conn.prepare_cached(
+                    "INSERT INTO nodes (path, type, size, time, depth, target, inode, dev, mode, prot, user, \"group\", error)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                )?;
+                stmt.execute(rusqlite::params![
+                    row.path,
+                    row.node_type,
+                    row.size.map(|size| size as i64),
+                    row.time,
+                    row.depth as i64,
+                    row.target,
+                    row.inode.map(|inode| inode as i64),
+                    row.dev.map(|dev| dev as i64),
+                    row.mode,
+                    row.prot,
+                    row.user,
+                    row.group,
+                    row.error,
+                ])?;
+            }
+        }
+        Ok(())
+    }
 
-#[derive(Debug, Clone)]
-struct BookmarkEntry {
-    name: String,
-    href: String,
-    add_date: u64,
-    last_modified: u64,
-    description: Option<String>,
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            TreeOutput::Tsv(mut w) | TreeOutput::Jsonl(mut w) => w.flush()?,
+            TreeOutput::Csv(mut w) => w.flush()?,
+            TreeOutput::Sqlite(conn) => conn.execute_batch("COMMIT;")?,
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone)]
-struct BookmarkFolder {
-    name: String,
-    last_modified: u64,
-    entries: Vec<BookmarkItem>,
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size: {}", value))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        "T" | "TB" => 1 << 40,
+        _ => return Err(format!("Invalid size unit: {}", unit)),
+    };
+    Ok((number * multiplier as f64) as u64)
 }
 
-#[derive(Debug, Clone)]
-enum BookmarkItem {
-    Link(BookmarkEntry),
-    Folder(BookmarkFolder),
+fn parse_filter_date(value: &str) -> Result<chrono::NaiveDate, String> {
+    let padded = match value.len() {
+        4 => format!("{}-01-01", value),
+        7 => format!("{}-01", value),
+        _ => value.to_string(),
+    };
+    chrono::NaiveDate::parse_from_str(&padded, "%Y-%m-%d")
+        .map_err(|_| format!("Invalid date (expected YYYY, YYYY-MM or YYYY-MM-DD): {}", value))
 }
 
-fn system_time_to_unix_timestamp(time: SystemTime) -> u64 {
-    time.duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs()
+// Times are whatever --timefmt produced; only ones starting with a
+// YYYY-MM-DD date can be compared
+fn parse_tree_time(time: &str) -> Option<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(time.get(..10)?, "%Y-%m-%d").ok()
 }
 
-fn encode_path_preserving_slashes(path: &Path) -> String {
-    path.components()
-        .map(|component| {
-            let s = component.as_os_str().to_string_lossy();
-            urlencoding::encode(&s).to_string()
-        })
-        .collect::<Vec<_>>()
-        .join("/")
+impl TreeFilter {
+    fn matches(&self, row: &TreeRow) -> bool {
+        if let Some(kind) = self.node_type {
+            let wanted = match kind {
+                TreeNodeKind::File => "file",
+                TreeNodeKind::Directory => "directory",
+                TreeNodeKind::Link => "link",
+            };
+            if row.node_type != wanted {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.glob {
+            if !pattern.matches(&row.path) {
+                return false;
+            }
+        }
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(&row.path) {
+                return false;
+            }
+        }
+        if self.min_size.is_some() || self.max_size.is_some() {
+            let Some(size) = row.size else { return false };
+            if self.min_size.is_some_and(|min| size < min) || self.max_size.is_some_and(|max| size > max) {
+                return false;
+            }
+        }
+        if self.after.is_some() || self.before.is_some() {
+            let Some(date) = row.time.as_deref().and_then(parse_tree_time) else { return false };
+            if self.after.is_some_and(|after| date < after) || self.before.is_some_and(|before| date >= before) {
+                return false;
+            }
+        }
+        true
+    }
 }
 
-fn get_file_metadata(path: &Path) -> Result<(u64, u64), Box<dyn Error>> {
-    let metadata = fs::metadata(path)?;
-    let modified = metadata.modified()?;
-    let created = metadata.created().unwrap_or(modified);
+#[test]
+fn test_tree_filter() {
+    let tree: TreeList = serde_json::from_str(r#"
+    [
+      {"type":"directory","name":"/Users/ojas/GDrive","size":3221229568,"time":"2023-08-23","contents":[
+        {"type":"file","name":"big.mov","size":2147483648,"time":"2019-07-07"},
+        {"type":"directory","name":"Archive","size":1073745920,"time":"2019-02-08","contents":[
+          {"type":"file","name":"XÚÖ.mkv","size":1073741824,"time":"2018-05-08"},
+          {"type":"file","name":"new.mkv","size":1073741824,"time":"2021-05-08"}
+        ]}
+      ]}
+    ]"#).unwrap();
+
+    // files over 1 GB modified before 2020 under Archive/
+    let filter = TreeFilter {
+        glob: Some(glob::Pattern::new("*/Archive/*").unwrap()),
+        min_size: Some(parse_size("1G").unwrap()),
+        before: Some(parse_filter_date("2020").unwrap()),
+        node_type: Some(TreeNodeKind::File),
+        ..Default::default()
+    };
+    let mut paths = Vec::new();
+    walk_tree_fullpath(&tree.roots[0], "", 0, &filter, &mut |row| {
+        paths.push(row.path);
+        Ok(())
+    }).unwrap();
+    assert_eq!(paths, vec!["/Users/ojas/GDrive/Archive/XÚÖ.mkv"]);
 
-    Ok((
-        system_time_to_unix_timestamp(created),
-        system_time_to_unix_timestamp(modified),
-    ))
-}
+    let filter = TreeFilter { max_depth: Some(1), ..Default::default() };
+    let mut count = 0;
+    walk_tree_fullpath(&tree.roots[0], "", 0, &filter, &mut |_| {
+        count += 1;
+        Ok(())
+    }).unwrap();
+    assert_eq!(count, 3);
 
-fn scan_directory(
-    dir_path: &Path,
-    index_filename: &str,
-    recursive: bool,
-) -> Result<(Vec<PathBuf>, Vec<PathBuf>), Box<dyn Error>> {
-    let mut files = Vec::new();
-    let mut dirs = Vec::new();
+    assert_eq!(parse_size("1.5K").unwrap(), 1536);
+    assert!(parse_size("12 parsecs").is_err());
+}
 
-    for entry in fs::read_dir(dir_path)? {
-        let entry = entry?;
-        let path = entry.path();
-        let file_name = entry.file_name();
-        let file_name_str = file_name.to_string_lossy();
+fn walk_tree_fullpath(
+    node: &TreeNode,
+    prefix: &str,
+    depth: usize,
+    filter: &TreeFilter,
+    emit: &mut dyn FnMut(TreeRow) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let full_path = format!("{}{}", prefix, &node.name);
+    let row = TreeRow::from_node(node, full_path.clone(), depth);
+    if filter.matches(&row) {
+        emit(row)?;
+    }
+    if filter.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+        return Ok(());
+    }
+    if let Some(contents) = &node.contents {
+        for child in contents {
+            walk_tree_fullpath(child, &format!("{}/", full_path), depth + 1, filter, emit)?;
+        }
+    }
+    Ok(())
+}
 
-        // Skip the index file itself
-        if file_name_str == index_filename {
-            continue;
+fn print_tree_summary(report: Option<&ReportNode>) {
+    match report {
+        Some(ReportNode { size: Some(size), directories, files, .. }) => {
+            eprintln!("{} directories, {} files, {} bytes", directories, files, size)
         }
+        Some(report) => eprintln!("{} directories, {} files", report.directories, report.files),
+        None => eprintln!("No report in tree output"),
+    }
+}
+
+fn handle_tree_command(
+    path: &Path,
+    format: TreeFormat,
+    output: Option<&Path>,
+    summary: bool,
+    filter: &TreeFilter,
+) -> Result<(), Box<dyn Error>> {
+    let mut out = TreeOutput::open(format, output)?;
+
+    // Rows are written as they are parsed, so snapshots larger than memory
+    // can still be listed
+    let report = stream_tree_source(path, filter, &mut |row| out.write_row(&row))?;
+    out.finish()?;
+
+    if summary {
+        print_tree_summary(report.as_ref());
+    }
+
+    Ok(())
+}
+
+// Streaming tree parser
+//
+// Walks a tree -J document with serde visitors, emitting a row for each node
+// as soon as its own fields have been read. Only the path of the current
+// node is kept, so memory use is bounded by the depth of the tree rather than
+// its size. tree writes "contents" last, so a node's row is emitted when its
+// contents start (or when its object ends, for files).
+
+struct TreeStream<'a> {
+    filter: &'a TreeFilter,
+    emit: &'a mut dyn FnMut(TreeRow) -> Result<(), Box<dyn Error>>,
+    report: Option<ReportNode>,
+}
+
+impl TreeStream<'_> {
+    fn emit_node(&mut self, node: &TreeNode, path: String, depth: usize) -> Result<(), Box<dyn Error>> {
+        let row = TreeRow::from_node(node, path, depth);
+        if self.filter.matches(&row) {
+            (self.emit)(row)?;
+        }
+        Ok(())
+    }
+}
+
+struct TreeStreamSeq<'a, 'b> {
+    stream: &'a mut TreeStream<'b>,
+    prefix: &'a str,
+    depth: usize,
+}
+
+struct TreeStreamNode<'a, 'b> {
+    stream: &'a mut TreeStream<'b>,
+    prefix: &'a str,
+    depth: usize,
+}
+
+impl<'de> serde::de::DeserializeSeed<'de> for TreeStreamSeq<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for TreeStreamSeq<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a list of tree nodes")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while seq.next_element_seed(TreeStreamNode {
+            stream: &mut *self.stream,
+            prefix: self.prefix,
+            depth: self.depth,
+        })?.is_some() {}
+        Ok(())
+    }
+}
+
+impl<'de> serde::de::DeserializeSeed<'de> for TreeStreamNode<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for TreeStreamNode<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a tree node or report")
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        use serde::de::{Error as _, IgnoredAny};
+
+        let mut node = TreeNode { node_type: default_tree_node_type(), ..Default::default() };
+        let mut directories = None;
+        let mut files = None;
+        let mut emitted = false;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "type" => node.node_type = map.next_value()?,
+                "name" => node.name = map.next_value()?,
+                "target" => node.target = map.next_value()?,
+                "inode" => node.inode = map.next_value()?,
+                "dev" => node.dev = map.next_value()?,
+                "mode" => node.mode = map.next_value()?,
+                "prot" => node.prot = map.next_value()?,
+                "user" => node.user = map.next_value()?,
+                "group" => node.group = map.next_value()?,
+                "size" => node.size = map.next_value()?,
+                "time" => node.time = map.next_value()?,
+                "error" => node.error = map.next_value()?,
+                "directories" => directories = Some(map.next_value()?),
+                "files" => files = Some(map.next_value()?),
+                "contents" => {
+                    let path = format!("{}{}", self.prefix, node.name);
+                    self.stream.emit_node(&node, path.clone(), self.depth).map_err(A::Error::custom)?;
+                    emitted = true;
+
+                    if self.stream.filter.max_depth.is_some_and(|max_depth| self.depth >= max_depth) {
+                        map.next_value::<IgnoredAny>()?;
+                    } else {
+                        let prefix = format!("{}/", path);
+                        map.next_value_seed(TreeStreamSeq {
+                            stream: &mut *self.stream,
+                            prefix: &prefix,
+                            depth: self.depth + 1,
+                        })?;
+                    }
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        if node.node_type == "report" {
+            self.stream.report = Some(ReportNode {
+                node_type: node.node_type,
+                size: node.size,
+                directories: directories.unwrap_or(0),
+                files: files.unwrap_or(0),
+            });
+        } else if !emitted {
+            let path = format!("{}{}", self.prefix, node.name);
+            self.stream.emit_node(&node, path, self.depth).map_err(A::Error::custom)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Returns the report, if the document has one
+fn stream_tree<R: io::Read>(
+    reader: R,
+    filter: &TreeFilter,
+    emit: &mut dyn FnMut(TreeRow) -> Result<(), Box<dyn Error>>,
+) -> Result<Option<ReportNode>, Box<dyn Error>> {
+    use serde::de::DeserializeSeed;
+
+    let mut stream = TreeStream { filter, emit, report: None };
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    TreeStreamSeq { stream: &mut stream, prefix: "", depth: 0 }.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+
+    Ok(stream.report)
+}
+
+// Emit the rows of a tree JSON file or a scanned directory. Files are
+// streamed, so only directory scans are held in memory
+fn stream_tree_source(
+    path: &Path,
+    filter: &TreeFilter,
+    emit: &mut dyn FnMut(TreeRow) -> Result<(), Box<dyn Error>>,
+) -> Result<Option<ReportNode>, Box<dyn Error>> {
+    if path.is_dir() {
+        let tree = scan_tree(path, DEFAULT_TREE_TIMEFMT, false)?;
+        for root in &tree.roots {
+            walk_tree_fullpath(root, "", 0, filter, emit)?;
+        }
+        Ok(tree.report)
+    } else {
+        stream_tree(BufReader::new(File::open(path)?), filter, emit)
+    }
+}
+
+#[test]
+fn test_stream_tree_matches_in_memory_walk() {
+    let data = r#"
+    [
+      {"type":"link","name":"/Users/ojas/GDrive","target":"/Volumes/GDrive","size":99984167157,"time":"2023-08-23","contents":[
+        {"type":"file","name":"2021-06 IG Posts.gsheet","size":168,"time":"2021-07-07"},
+        {"type":"directory","name":"Archive","size":577632,"time":"2019-02-08","contents":[
+          {"type":"file","name":"XÚÖ West.gdoc","size":168,"time":"2018-05-08","mode":"0644","user":"ojas"},
+          {"type":"directory","name":"Private","contents":[{"error": "opening dir"}]}
+        ]}
+      ]},
+      {"type":"directory","name":"/Volumes/Photos","contents":[]},
+      {"type":"report","size":99984167157,"directories":909,"files":29484}
+    ]"#;
+
+    for filter in [
+        TreeFilter::default(),
+        TreeFilter { max_depth: Some(1), ..Default::default() },
+        TreeFilter { node_type: Some(TreeNodeKind::File), ..Default::default() },
+    ] {
+        let tree: TreeList = serde_json::from_str(data).unwrap();
+        let mut expected = Vec::new();
+        for root in &tree.roots {
+            walk_tree_fullpath(root, "", 0, &filter, &mut |row| {
+                expected.push(row);
+                Ok(())
+            }).unwrap();
+        }
+
+        let mut streamed = Vec::new();
+        let report = stream_tree(data.as_bytes(), &filter, &mut |row| {
+            streamed.push(row);
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(streamed, expected);
+        assert_eq!(report.unwrap().files, 29484);
+    }
+}
+
+fn handle_tree_scan_command(
+    path: &Path,
+    output: Option<&Path>,
+    timefmt: &str,
+    all: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !path.is_dir() {
+        return Err(format!("Path is not a directory: {}", path.display()).into());
+    }
+
+    let tree = scan_tree(path, timefmt, all)?;
+
+    match output {
+        Some(output_path) => {
+            let mut writer = BufWriter::new(File::create(output_path)?);
+            write_tree_json(&tree, &mut writer)?;
+            writeln!(writer)?;
+            writer.flush()?;
+        }
+        None => {
+            let mut writer = io::stdout().lock();
+            write_tree_json(&tree, &mut writer)?;
+            writeln!(writer)?;
+        }
+    }
+
+    print_tree_summary(tree.report.as_ref());
+
+    Ok(())
+}
+
+// Temporary directory for fixture tests, removed again when the test ends
+// or fails
+#[cfg(test)]
+struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    fn new(name: &str) -> TestDir {
+        let dir = std::env::temp_dir().join(format!("sbt-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_scan_tree_round_trip() {
+    let tmp = TestDir::new("scan");
+    let dir = tmp.path();
+    fs::create_dir_all(dir.join("Archive")).unwrap();
+    fs::write(dir.join("Archive/XÚÖ.txt"), "hello").unwrap();
+    fs::write(dir.join("notes.txt"), "hello world").unwrap();
+    fs::write(dir.join(".hidden"), "skipped").unwrap();
+
+    assert!(scan_tree(dir, "%Q", false).is_err());
+    let tree = scan_tree(dir, DEFAULT_TREE_TIMEFMT, false).unwrap();
+    let mut json = Vec::new();
+    write_tree_json(&tree, &mut json).unwrap();
+    let parsed: TreeList = serde_json::from_slice(&json).unwrap();
+
+    let report = parsed.report.unwrap();
+    let root = &parsed.roots[0];
+    assert_eq!(report.directories, 1);
+    assert_eq!(report.files, 2);
+    let contents = root.contents.as_ref().unwrap();
+    assert_eq!(contents.len(), 2);
+    assert_eq!(contents[0].name, "Archive");
+    assert_eq!(contents[1].size, Some(11));
+    // Directory sizes include everything below them
+    assert!(root.size.unwrap() >= 16);
+    assert_eq!(report.size, root.size);
+}
+
+// Tree diff functionality
+
+#[derive(Serialize, Debug)]
+struct TreeDiffEntry {
+    path: String,
+    #[serde(rename = "type")]
+    node_type: String,
+    size: Option<u64>,
+    time: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct TreeDiffChange {
+    path: String,
+    #[serde(rename = "type")]
+    node_type: String,
+    old_size: Option<u64>,
+    new_size: Option<u64>,
+    delta: Option<i64>,
+    old_time: Option<String>,
+    new_time: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct TreeDirDelta {
+    path: String,
+    old_size: u64,
+    new_size: u64,
+    delta: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct TreeDiff {
+    added: Vec<TreeDiffEntry>,
+    removed: Vec<TreeDiffEntry>,
+    resized: Vec<TreeDiffChange>,
+    retimed: Vec<TreeDiffChange>,
+    directory_deltas: Vec<TreeDirDelta>,
+    added_bytes: u64,
+    removed_bytes: u64,
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn format_size_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{}{}", sign, format_size(delta.unsigned_abs()))
+}
+
+// Snapshots taken without --du have no sizes to show
+fn format_optional_size(size: Option<u64>) -> String {
+    size.map(format_size).unwrap_or_else(|| "-".to_string())
+}
+
+// Flatten a tree into rows keyed by path relative to its root, so snapshots
+// taken from different mount points still line up. A single root is "."; with
+// several roots each one keeps its name so their contents don't collide.
+fn tree_rows_by_relative_path(tree: &TreeList) -> Result<HashMap<String, TreeRow>, Box<dyn Error>> {
+    let mut rows = HashMap::new();
+    let single_root = tree.roots.len() == 1;
+    for root in &tree.roots {
+        let root_prefix_len = root.name.len() + 1;
+        walk_tree_fullpath(root, "", 0, &TreeFilter::default(), &mut |row| {
+            let relative = if row.depth == 0 { "." } else { &row.path[root_prefix_len..] };
+            let key = if single_root {
+                relative.to_string()
+            } else if row.depth == 0 {
+                root.name.clone()
+            } else {
+                format!("{}/{}", root.name, relative)
+            };
+            rows.insert(key, row);
+            Ok(())
+        })?;
+    }
+    Ok(rows)
+}
+
+fn diff_trees(old: &TreeList, new: &TreeList) -> Result<TreeDiff, Box<dyn Error>> {
+    let old_rows = tree_rows_by_relative_path(old)?;
+    let new_rows = tree_rows_by_relative_path(new)?;
+
+    let mut diff = TreeDiff {
+        added: Vec::new(),
+        removed: Vec::new(),
+        resized: Vec::new(),
+        retimed: Vec::new(),
+        directory_deltas: Vec::new(),
+        added_bytes: 0,
+        removed_bytes: 0,
+    };
+
+    for (path, new_row) in &new_rows {
+        match old_rows.get(path) {
+            None => {
+                if new_row.node_type != "directory" {
+                    diff.added_bytes += new_row.size.unwrap_or(0);
+                }
+                diff.added.push(TreeDiffEntry {
+                    path: path.clone(),
+                    node_type: new_row.node_type.clone(),
+                    size: new_row.size,
+                    time: new_row.time.clone(),
+                });
+            }
+            // Directory sizes are reported separately as deltas, and their
+            // timestamps change whenever their contents do
+            Some(_) if new_row.node_type == "directory" || new_row.depth == 0 => {}
+            Some(old_row) => {
+                let change = TreeDiffChange {
+                    path: path.clone(),
+                    node_type: new_row.node_type.clone(),
+                    old_size: old_row.size,
+                    new_size: new_row.size,
+                    delta: old_row.size.zip(new_row.size).map(|(o, n)| n as i64 - o as i64),
+                    old_time: old_row.time.clone(),
+                    new_time: new_row.time.clone(),
+                };
+                if old_row.size != new_row.size {
+                    diff.resized.push(change);
+                } else if old_row.time != new_row.time {
+                    diff.retimed.push(change);
+                }
+            }
+        }
+    }
+
+    for (path, old_row) in &old_rows {
+        if !new_rows.contains_key(path) {
+            if old_row.node_type != "directory" {
+                diff.removed_bytes += old_row.size.unwrap_or(0);
+            }
+            diff.removed.push(TreeDiffEntry {
+                path: path.clone(),
+                node_type: old_row.node_type.clone(),
+                size: old_row.size,
+                time: old_row.time.clone(),
+            });
+        }
+    }
+
+    // Size deltas for every directory present in either snapshot
+    let dir_paths: HashSet<&String> = old_rows.iter()
+        .chain(new_rows.iter())
+        .filter(|(_, row)| row.node_type == "directory" || row.depth == 0)
+        .map(|(path, _)| path)
+        .collect();
+    for path in dir_paths {
+        // A directory missing from one side counts as empty; one present
+        // without a size (no --du) can't be compared
+        let old_size = match old_rows.get(path) {
+            Some(row) => match row.size { Some(size) => size, None => continue },
+            None => 0,
+        };
+        let new_size = match new_rows.get(path) {
+            Some(row) => match row.size { Some(size) => size, None => continue },
+            None => 0,
+        };
+        if old_size != new_size {
+            diff.directory_deltas.push(TreeDirDelta {
+                path: path.clone(),
+                old_size,
+                new_size,
+                delta: new_size as i64 - old_size as i64,
+            });
+        }
+    }
+
+    diff.added.sort_by(|a, b| a.path.cmp(&b.path));
+    diff.removed.sort_by(|a, b| a.path.cmp(&b.path));
+    diff.resized.sort_by(|a, b| a.path.cmp(&b.path));
+    diff.retimed.sort_by(|a, b| a.path.cmp(&b.path));
+    // Largest changes first, so big deletions are at the top
+    diff.directory_deltas.sort_by(|a, b| {
+        b.delta.unsigned_abs().cmp(&a.delta.unsigned_abs()).then(a.path.cmp(&b.path))
+    });
+
+    Ok(diff)
+}
+
+fn print_tree_diff(diff: &TreeDiff) {
+    println!("Added ({}):", diff.added.len());
+    for entry in &diff.added {
+        println!(
+            "  + {}\t{}\t{}",
+            entry.path,
+            format_optional_size(entry.size),
+            entry.time.as_deref().unwrap_or("-")
+        );
+    }
+    println!("Removed ({}):", diff.removed.len());
+    for entry in &diff.removed {
+        println!(
+            "  - {}\t{}\t{}",
+            entry.path,
+            format_optional_size(entry.size),
+            entry.time.as_deref().unwrap_or("-")
+        );
+    }
+    println!("Resized ({}):", diff.resized.len());
+    for change in &diff.resized {
+        println!(
+            "  ~ {}\t{} -> {} ({})",
+            change.path,
+            format_optional_size(change.old_size),
+            format_optional_size(change.new_size),
+            change.delta.map(format_size_delta).unwrap_or_default()
+        );
+    }
+    println!("Retimed ({}):", diff.retimed.len());
+    for change in &diff.retimed {
+        println!(
+            "  ~ {}\t{} -> {}",
+            change.path,
+            change.old_time.as_deref().unwrap_or("-"),
+            change.new_time.as_deref().unwrap_or("-")
+        );
+    }
+    println!("Directory size deltas ({}):", diff.directory_deltas.len());
+    for delta in &diff.directory_deltas {
+        println!(
+            "  {}\t{}\t({} -> {})",
+            delta.path,
+            format_size_delta(delta.delta),
+            format_size(delta.old_size),
+            format_size(delta.new_size)
+        );
+    }
+    println!(
+        "\n{} added ({}), {} removed ({}), {} resized, {} retimed",
+        diff.added.len(),
+        format_size(diff.added_bytes),
+        diff.removed.len(),
+        format_size(diff.removed_bytes),
+        diff.resized.len(),
+        diff.retimed.len()
+    );
+}
+
+fn handle_tree_diff_command(old_path: &Path, new_path: &Path, format: DiffFormat) -> Result<(), Box<dyn Error>> {
+    let old = load_tree(old_path)?;
+    let new = load_tree(new_path)?;
+    let diff = diff_trees(&old, &new)?;
+
+    match format {
+        DiffFormat::Text => print_tree_diff(&diff),
+        DiffFormat::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_diff_trees() {
+    let old: TreeList = serde_json::from_str(r#"
+    [
+      {"type":"directory","name":"/Users/ojas/GDrive","size":1500,"time":"2023-08-23","contents":[
+        {"type":"file","name":"budget.gsheet","size":168,"time":"2021-07-07"},
+        {"type":"file","name":"notes.txt","size":300,"time":"2021-07-07"},
+        {"type":"directory","name":"Archive","size":1032,"time":"2019-02-08","contents":[
+          {"type":"file","name":"XÚÖ.gsite","size":1000,"time":"2018-05-10"}
+        ]}
+      ]},
+      {"type":"report","size":1500,"directories":1,"files":3}
+    ]"#).unwrap();
+    let new: TreeList = serde_json::from_str(r#"
+    [
+      {"type":"directory","name":"/Volumes/Backup/GDrive","size":700,"time":"2023-09-01","contents":[
+        {"type":"file","name":"budget.gsheet","size":168,"time":"2023-09-01"},
+        {"type":"file","name":"notes.txt","size":500,"time":"2023-09-01"},
+        {"type":"directory","name":"Archive","size":32,"time":"2023-09-01","contents":[]}
+      ]},
+      {"type":"report","size":700,"directories":1,"files":2}
+    ]"#).unwrap();
+
+    let diff = diff_trees(&old, &new).unwrap();
+
+    assert!(diff.added.is_empty());
+    assert_eq!(diff.removed.len(), 1);
+    assert_eq!(diff.removed[0].path, "Archive/XÚÖ.gsite");
+    assert_eq!(diff.removed_bytes, 1000);
+    assert_eq!(diff.resized.len(), 1);
+    assert_eq!(diff.resized[0].delta, Some(200));
+    assert_eq!(diff.retimed.len(), 1);
+    assert_eq!(diff.retimed[0].path, "budget.gsheet");
+    assert_eq!(diff.directory_deltas[0].path, "Archive");
+    assert_eq!(diff.directory_deltas[0].delta, -1000);
+}
+
+// Tree disk-usage functionality
+
+struct DuEntry {
+    path: String,
+    size: u64,
+    parent_size: u64,
+}
+
+// The largest `limit` entries seen so far, plus a count of all of them.
+// Ties go to the path that sorts first, as in the printed listing
+struct DuTop {
+    limit: usize,
+    heap: BinaryHeap<Reverse<(u64, Reverse<String>, u64)>>,
+    seen: usize,
+}
+
+impl DuTop {
+    fn new(limit: usize) -> DuTop {
+        DuTop { limit, heap: BinaryHeap::new(), seen: 0 }
+    }
+
+    fn push(&mut self, size: u64, path: String, parent_size: u64) {
+        self.seen += 1;
+        self.insert(size, path, parent_size);
+    }
+
+    fn insert(&mut self, size: u64, path: String, parent_size: u64) {
+        self.heap.push(Reverse((size, Reverse(path), parent_size)));
+        if self.heap.len() > self.limit {
+            self.heap.pop();
+        }
+    }
+
+    // Only a node's own largest children can be among the largest overall
+    fn absorb(&mut self, children: DuTop, parent_size: u64) {
+        self.seen += children.seen;
+        for Reverse((size, Reverse(path), _)) in children.heap {
+            self.insert(size, path, parent_size);
+        }
+    }
+
+    // Largest first
+    fn into_entries(self) -> Vec<DuEntry> {
+        self.heap.into_sorted_vec().into_iter()
+            .map(|Reverse((size, Reverse(path), parent_size))| DuEntry { path, size, parent_size })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct DuBucket {
+    files: u64,
+    bytes: u64,
+}
+
+struct DuReport {
+    top: usize,
+    roots: Vec<(String, u64)>,
+    directories: DuTop,
+    files: DuTop,
+    by_extension: HashMap<String, DuBucket>,
+    by_year: HashMap<String, DuBucket>,
+    total_file_bytes: u64,
+    open: Vec<DuFrame>,
+}
+
+// A node whose size may still depend on contents that haven't been seen
+// yet. Its children only keep their total and the largest few
+struct DuFrame {
+    row: TreeRow,
+    children_bytes: u64,
+    directories: DuTop,
+    files: DuTop,
+}
+
+impl DuReport {
+    fn new(top: usize) -> DuReport {
+        DuReport {
+            top,
+            roots: Vec::new(),
+            directories: DuTop::new(top),
+            files: DuTop::new(top),
+            by_extension: HashMap::new(),
+            by_year: HashMap::new(),
+            total_file_bytes: 0,
+            open: Vec::new(),
+        }
+    }
+
+    // Rows arrive in tree order, so every open node at this depth or deeper
+    // is complete
+    fn add_row(&mut self, row: TreeRow) {
+        self.close_frames(row.depth);
+        self.open.push(DuFrame {
+            row,
+            children_bytes: 0,
+            directories: DuTop::new(self.top),
+            files: DuTop::new(self.top),
+        });
+    }
+
+    fn finish(&mut self) {
+        self.close_frames(0);
+    }
+
+    // Sizes fall back to the sum of a node's contents for listings made
+    // without --du
+    fn close_frames(&mut self, depth: usize) {
+        while self.open.last().is_some_and(|frame| frame.row.depth >= depth) {
+            let frame = self.open.pop().unwrap();
+            let size = frame.row.size.unwrap_or(frame.children_bytes);
+            self.directories.absorb(frame.directories, size);
+            self.files.absorb(frame.files, size);
+
+            if frame.row.node_type == "file" {
+                let extension = Path::new(&frame.row.path)
+                    .extension()
+                    .map(|ext| ext.to_string_lossy().to_lowercase())
+                    .unwrap_or_else(|| "(none)".to_string());
+                let year = frame.row.time.as_deref()
+                    .and_then(parse_tree_time)
+                    .map(|date| date.format("%Y").to_string())
+                    .unwrap_or_else(|| "(unknown)".to_string());
+
+                for bucket in [
+                    self.by_extension.entry(extension).or_default(),
+                    self.by_year.entry(year).or_default(),
+                ] {
+                    bucket.files += 1;
+                    bucket.bytes += size;
+                }
+                self.total_file_bytes += size;
+            }
+
+            match self.open.last_mut() {
+                Some(parent) => {
+                    parent.children_bytes += size;
+                    let siblings = if frame.row.node_type == "directory" { &mut parent.directories } else { &mut parent.files };
+                    siblings.push(size, frame.row.path, 0);
+                }
+                None => self.roots.push((frame.row.path, size)),
+            }
+        }
+    }
+}
+
+fn format_percent(part: u64, whole: u64) -> String {
+    if whole == 0 {
+        "-".to_string()
+    } else {
+        format!("{:.1}%", part as f64 * 100.0 / whole as f64)
+    }
+}
+
+fn print_du_entries(title: &str, top: DuTop) {
+    let seen = top.seen;
+    let entries = top.into_entries();
+    println!("{} (top {} of {}):", title, entries.len(), seen);
+    println!("  {:>10}  {:>7}  PATH", "SIZE", "%PARENT");
+    for entry in entries {
+        println!(
+            "  {:>10}  {:>7}  {}",
+            format_size(entry.size),
+            format_percent(entry.size, entry.parent_size),
+            entry.path
+        );
+    }
+    println!();
+}
+
+fn print_du_buckets(title: &str, buckets: Vec<(&String, &DuBucket)>, total: u64) {
+    println!("{}:", title);
+    println!("  {:>10}  {:>7}  {:>8}  {}", "SIZE", "%TOTAL", "FILES", title.to_uppercase());
+    for (name, bucket) in buckets {
+        println!(
+            "  {:>10}  {:>7}  {:>8}  {}",
+            format_size(bucket.bytes),
+            format_percent(bucket.bytes, total),
+            bucket.files,
+            name
+        );
+    }
+    println!();
+}
+
+fn handle_tree_du_command(path: &Path, top: usize) -> Result<(), Box<dyn Error>> {
+    let mut report = DuReport::new(top);
+    stream_tree_source(path, &TreeFilter::default(), &mut |row| {
+        report.add_row(row);
+        Ok(())
+    })?;
+    report.finish();
+
+    for (root, size) in &report.roots {
+        println!("{}\t{}", format_size(*size), root);
+    }
+    println!();
+
+    print_du_entries("Largest directories", report.directories);
+    print_du_entries("Largest files", report.files);
+
+    let mut by_extension: Vec<_> = report.by_extension.iter().collect();
+    by_extension.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(b.0)));
+    by_extension.truncate(top);
+    print_du_buckets("Extension", by_extension, report.total_file_bytes);
+
+    let mut by_year: Vec<_> = report.by_year.iter().collect();
+    by_year.sort_by(|a, b| a.0.cmp(b.0));
+    print_du_buckets("Year", by_year, report.total_file_bytes);
+
+    Ok(())
+}
+
+#[test]
+fn test_du_report() {
+    let tree: TreeList = serde_json::from_str(r#"
+    [
+      {"type":"directory","name":"/Users/ojas/GDrive","time":"2023-08-23","contents":[
+        {"type":"file","name":"clip.MOV","size":3000,"time":"2019-07-07"},
+        {"type":"directory","name":"Archive","time":"2019-02-08","contents":[
+          {"type":"file","name":"XÚÖ.mov","size":1000,"time":"2018-05-08"},
+          {"type":"file","name":"README","size":500,"time":"2018-05-08"}
+        ]}
+      ]}
+    ]"#).unwrap();
+
+    // Only the largest file is kept, but all of them are counted
+    let mut report = DuReport::new(1);
+    walk_tree_fullpath(&tree.roots[0], "", 0, &TreeFilter::default(), &mut |row| {
+        report.add_row(row);
+        Ok(())
+    }).unwrap();
+    report.finish();
+
+    // No --du sizes on directories, so they are summed from their contents
+    assert_eq!(report.roots, vec![("/Users/ojas/GDrive".to_string(), 4500)]);
+
+    assert_eq!(report.by_extension["mov"].files, 2);
+    assert_eq!(report.by_extension["mov"].bytes, 4000);
+    assert_eq!(report.by_extension["(none)"].bytes, 500);
+    assert_eq!(report.by_year["2018"].bytes, 1500);
+    assert_eq!(report.total_file_bytes, 4500);
+
+    assert_eq!(report.directories.seen, 1);
+    let directories = report.directories.into_entries();
+    assert_eq!((directories[0].size, directories[0].parent_size), (1500, 4500));
+    assert_eq!(report.files.seen, 3);
+    let files = report.files.into_entries();
+    assert_eq!(files.len(), 1);
+    assert_eq!((files[0].path.as_str(), files[0].size, files[0].parent_size), ("/Users/ojas/GDrive/clip.MOV", 3000, 4500));
+
+    // Equal sizes keep the paths that sort first
+    let mut top = DuTop::new(2);
+    for (path, size) in [("c", 5), ("a", 5), ("big", 9), ("b", 5), ("small", 1)] {
+        top.push(size, path.to_string(), 20);
+    }
+    let paths: Vec<_> = top.into_entries().into_iter().map(|e| e.path).collect();
+    assert_eq!(paths, ["big", "a"]);
+}
+
+// Tree duplicate detection functionality
+
+#[derive(Debug)]
+struct DupeFile {
+    snapshot: String,
+    path: String,
+}
+
+#[derive(Debug)]
+struct DupeGroup {
+    name: String,
+    size: u64,
+    // Set when every file in the group was hashed and matched
+    hash: Option<String>,
+    files: Vec<DupeFile>,
+}
+
+impl DupeGroup {
+    fn wasted_bytes(&self) -> u64 {
+        self.size * (self.files.len() as u64 - 1)
+    }
+}
+
+fn hash_file(path: &Path) -> Result<String, Box<dyn Error>> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    let mut file = File::open(path)?;
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Files sharing a name and size, keyed by both
+type DupeCandidates = HashMap<(String, u64), Vec<DupeFile>>;
+
+// Only files of at least min_size can be duplicates
+fn dupe_candidate_filter(min_size: u64) -> TreeFilter {
+    TreeFilter {
+        node_type: Some(TreeNodeKind::File),
+        min_size: Some(min_size),
+        ..Default::default()
+    }
+}
+
+fn add_dupe_candidate(candidates: &mut DupeCandidates, snapshot: &str, row: TreeRow) {
+    let name = row.path.rsplit('/').next().unwrap_or(&row.path).to_string();
+    candidates.entry((name, row.size.unwrap_or(0))).or_default().push(DupeFile {
+        snapshot: snapshot.to_string(),
+        path: row.path,
+    });
+}
+
+fn find_dupes(candidates: DupeCandidates, hash: bool) -> Vec<DupeGroup> {
+    let mut groups = Vec::new();
+    for ((name, size), files) in candidates {
+        if files.len() < 2 {
+            continue;
+        }
+
+        // Only split by content when every copy can be read, otherwise a
+        // missing drive would make real duplicates look unique
+        let hashes: Option<Vec<String>> = if hash {
+            files.iter().map(|file| hash_file(Path::new(&file.path)).ok()).collect()
+        } else {
+            None
+        };
+
+        match hashes {
+            Some(hashes) => {
+                let mut by_hash: HashMap<String, Vec<DupeFile>> = HashMap::new();
+                for (file, file_hash) in files.into_iter().zip(hashes) {
+                    by_hash.entry(file_hash).or_default().push(file);
+                }
+                for (file_hash, files) in by_hash {
+                    if files.len() > 1 {
+                        groups.push(DupeGroup { name: name.clone(), size, hash: Some(file_hash), files });
+                    }
+                }
+            }
+            None => groups.push(DupeGroup { name, size, hash: None, files }),
+        }
+    }
+
+    groups.sort_by(|a, b| b.wasted_bytes().cmp(&a.wasted_bytes()).then(a.name.cmp(&b.name)));
+    groups
+}
+
+fn handle_tree_dupes_command(paths: &[PathBuf], hash: bool, min_size: u64) -> Result<(), Box<dyn Error>> {
+    let filter = dupe_candidate_filter(min_size);
+    let mut candidates = DupeCandidates::new();
+    for path in paths {
+        let snapshot = path.display().to_string();
+        stream_tree_source(path, &filter, &mut |row| {
+            add_dupe_candidate(&mut candidates, &snapshot, row);
+            Ok(())
+        })?;
+    }
+
+    let groups = find_dupes(candidates, hash);
+
+    let mut total_wasted = 0;
+    for group in &groups {
+        total_wasted += group.wasted_bytes();
+        let verified = if hash && group.hash.is_none() { " (unverified)" } else { "" };
+        println!(
+            "{} wasted: {} x {} ({}){}",
+            format_size(group.wasted_bytes()),
+            group.files.len(),
+            group.name,
+            format_size(group.size),
+            verified
+        );
+        for file in &group.files {
+            if paths.len() > 1 {
+                println!("    {}: {}", file.snapshot, file.path);
+            } else {
+                println!("    {}", file.path);
+            }
+        }
+    }
+
+    println!("\n{} duplicate groups, {} wasted", groups.len(), format_size(total_wasted));
+
+    Ok(())
+}
+
+#[test]
+fn test_find_dupes() {
+    let gdrive = r#"
+    [
+      {"type":"directory","name":"/Users/ojas/GDrive","size":9000,"time":"2023-08-23","contents":[
+        {"type":"directory","name":"Photos","size":5000,"time":"2023-08-23","contents":[
+          {"type":"file","name":"DSCF4462.JPG","size":4000,"time":"2023-08-23"},
+          {"type":"file","name":"XÚÖ.JPG","size":1000,"time":"2023-08-23"}
+        ]},
+        {"type":"file","name":"empty.txt","size":0,"time":"2023-08-23"}
+      ]}
+    ]"#;
+    let local = r#"
+    [
+      {"type":"directory","name":"/Volumes/Photos","size":9000,"time":"2023-08-23","contents":[
+        {"type":"file","name":"DSCF4462.JPG","size":4000,"time":"2023-08-23"},
+        {"type":"file","name":"XÚÖ.JPG","size":1200,"time":"2023-08-23"},
+        {"type":"file","name":"empty.txt","size":0,"time":"2023-08-23"}
+      ]}
+    ]"#;
+
+    let mut candidates = DupeCandidates::new();
+    for (snapshot, data) in [("gdrive", gdrive), ("local", local)] {
+        stream_tree(data.as_bytes(), &dupe_candidate_filter(1), &mut |row| {
+            add_dupe_candidate(&mut candidates, snapshot, row);
+            Ok(())
+        }).unwrap();
+    }
+    let groups = find_dupes(candidates, false);
+
+    // Different sizes and empty files are not duplicates
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].name, "DSCF4462.JPG");
+    assert_eq!(groups[0].wasted_bytes(), 4000);
+    assert_eq!(groups[0].files.len(), 2);
+}
+
+// Tree HTML report functionality
+
+const TREE_REPORT_TEMPLATE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="UTF-8">
+<title>{{TITLE}}</title>
+<style>
+  body { font: 14px system-ui, sans-serif; margin: 0; color: #222; }
+  header { display: flex; gap: 1em; align-items: center; padding: 0.5em 1em; background: #f3f3f3; border-bottom: 1px solid #ddd; }
+  header h1 { font-size: 1.1em; margin: 0; flex: 1; }
+  main { padding: 0.5em 1em; }
+  ul { list-style: none; margin: 0; padding-left: 1.2em; }
+  #tree > ul { padding-left: 0; }
+  .row { display: flex; align-items: center; gap: 0.6em; padding: 1px 0; cursor: default; }
+  .row.dir { cursor: pointer; }
+  .toggle { width: 1em; color: #888; }
+  .name { flex: 1; white-space: nowrap; overflow: hidden; text-overflow: ellipsis; }
+  .bar { width: 120px; height: 8px; background: #eee; }
+  .bar span { display: block; height: 100%; background: #4a90d9; }
+  .size, .time { width: 6em; text-align: right; color: #555; font-variant-numeric: tabular-nums; }
+  .time { width: 7em; }
+  #crumbs { margin-bottom: 0.5em; }
+  #crumbs a { color: #4a90d9; cursor: pointer; }
+  #map { position: relative; height: 75vh; border: 1px solid #ccc; }
+  .cell { position: absolute; box-sizing: border-box; border: 1px solid #fff; overflow: hidden; font-size: 11px; padding: 2px; color: #fff; }
+  .cell.dir { cursor: zoom-in; }
+</style>
+</head>
+<body>
+<header>
+  <h1>{{TITLE}}</h1>
+  <label>Sort by
+    <select id="sort">
+      <option value="size">size</option>
+      <option value="time">date</option>
+      <option value="name">name</option>
+    </select>
+  </label>
+  <button id="show-tree">Tree</button>
+  <button id="show-map">Treemap</button>
+</header>
+<main>
+  <div id="tree"></div>
+  <div id="map-view" hidden><div id="crumbs"></div><div id="map"></div></div>
+</main>
+<script id="data" type="application/json">{{DATA}}</script>
+<script>
+const roots = JSON.parse(document.getElementById("data").textContent)
+  .filter(item => item.type !== "report");
+
+function prepare(node) {
+  node.children = (node.contents || []).filter(child => child.name).map(prepare);
+  node.isDir = node.children.length > 0 || node.type === "directory";
+  node.total = node.size != null ? node.size : node.children.reduce((sum, c) => sum + c.total, 0);
+  return node;
+}
+const rootNode = roots.length === 1
+  ? prepare(roots[0])
+  : prepare({ name: document.title, type: "directory", contents: roots });
+
+function formatSize(bytes) {
+  const units = ["B", "KB", "MB", "GB", "TB"];
+  let size = bytes, unit = 0;
+  while (size >= 1024 && unit < units.length - 1) { size /= 1024; unit++; }
+  return unit === 0 ? bytes + " B" : size.toFixed(1) + " " + units[unit];
+}
+
+function sorted(children) {
+  const key = document.getElementById("sort").value;
+  const compare = {
+    size: (a, b) => b.total - a.total,
+    time: (a, b) => (b.time || "").localeCompare(a.time || ""),
+    name: (a, b) => a.name.localeCompare(b.name),
+  }[key];
+  return children.slice().sort(compare);
+}
+
+function renderChildren(node, li) {
+  const ul = document.createElement("ul");
+  for (const child of sorted(node.children)) ul.appendChild(renderNode(child, node));
+  li.appendChild(ul);
+  return ul;
+}
+
+function renderNode(node, parent) {
+  const li = document.createElement("li");
+  const row = document.createElement("div");
+  row.className = "row" + (node.isDir ? " dir" : "");
+  const pct = parent && parent.total > 0 ? (100 * node.total / parent.total) : 100;
+  const toggle = document.createElement("span");
+  toggle.className = "toggle";
+  toggle.textContent = node.isDir ? "▸" : "";
+  const name = document.createElement("span");
+  name.className = "name";
+  name.textContent = node.name + (node.target ? " → " + node.target : "");
+  name.title = pct.toFixed(1) + "% of parent";
+  const bar = document.createElement("span");
+  bar.className = "bar";
+  bar.innerHTML = '<span style="width:' + pct.toFixed(1) + '%"></span>';
+  const size = document.createElement("span");
+  size.className = "size";
+  size.textContent = formatSize(node.total);
+  const time = document.createElement("span");
+  time.className = "time";
+  time.textContent = node.time || "";
+  row.append(toggle, name, bar, size, time);
+  li.appendChild(row);
+
+  // Children are only built when a directory is first opened, so large
+  // listings stay responsive
+  let ul = null;
+  if (node.isDir) {
+    row.addEventListener("click", () => {
+      if (!ul) ul = renderChildren(node, li);
+      else ul.hidden = !ul.hidden;
+      toggle.textContent = ul.hidden ? "▸" : "▾";
+    });
+  }
+  return li;
+}
+
+function renderTree() {
+  const container = document.getElementById("tree");
+  container.innerHTML = "";
+  const ul = document.createElement("ul");
+  ul.appendChild(renderNode(rootNode, null));
+  container.appendChild(ul);
+  ul.querySelector(".row").click();
+}
+
+// Squarified treemap layout (Bruls, Huizing, van Wijk)
+function worst(row, side) {
+  const sum = row.reduce((s, r) => s + r.area, 0);
+  let max = 0, min = Infinity;
+  for (const r of row) { max = Math.max(max, r.area); min = Math.min(min, r.area); }
+  return Math.max(side * side * max / (sum * sum), (sum * sum) / (side * side * min));
+}
+
+function squarify(nodes, x, y, w, h) {
+  const total = nodes.reduce((s, n) => s + n.total, 0);
+  const rects = [];
+  if (total <= 0) return rects;
+  let rest = nodes.filter(n => n.total > 0).map(n => ({ node: n, area: n.total * w * h / total }));
+  while (rest.length) {
+    const side = Math.min(w, h);
+    const row = [rest[0]];
+    let best = worst(row, side), i = 1;
+    while (i < rest.length) {
+      const next = worst(row.concat([rest[i]]), side);
+      if (next > best) break;
+      row.push(rest[i]);
+      best = next;
+      i++;
+    }
+    rest = rest.slice(i);
+    const thick = row.reduce((s, r) => s + r.area, 0) / side;
+    let offset = 0;
+    for (const r of row) {
+      const len = r.area / thick;
+      if (w >= h) rects.push({ node: r.node, x: x, y: y + offset, w: thick, h: len });
+      else rects.push({ node: r.node, x: x + offset, y: y, w: len, h: thick });
+      offset += len;
+    }
+    if (w >= h) { x += thick; w -= thick; } else { y += thick; h -= thick; }
+  }
+  return rects;
+}
+
+function color(node) {
+  if (node.isDir) return "#4a6fa5";
+  const ext = node.name.includes(".") ? node.name.split(".").pop().toLowerCase() : "";
+  let hash = 0;
+  for (const c of ext) hash = (hash * 31 + c.charCodeAt(0)) % 360;
+  return "hsl(" + hash + ", 55%, 50%)";
+}
+
+let mapPath = [rootNode];
+
+function renderMap() {
+  const current = mapPath[mapPath.length - 1];
+  const crumbs = document.getElementById("crumbs");
+  crumbs.innerHTML = "";
+  mapPath.forEach((node, i) => {
+    const link = document.createElement("a");
+    link.textContent = node.name;
+    link.addEventListener("click", () => { mapPath = mapPath.slice(0, i + 1); renderMap(); });
+    if (i > 0) crumbs.append(" / ");
+    crumbs.appendChild(link);
+  });
+  crumbs.append(" (" + formatSize(current.total) + ")");
+
+  const map = document.getElementById("map");
+  map.innerHTML = "";
+  const children = current.children.slice().sort((a, b) => b.total - a.total);
+  for (const rect of squarify(children, 0, 0, map.clientWidth, map.clientHeight)) {
+    const cell = document.createElement("div");
+    cell.className = "cell" + (rect.node.isDir ? " dir" : "");
+    cell.style.left = rect.x + "px";
+    cell.style.top = rect.y + "px";
+    cell.style.width = rect.w + "px";
+    cell.style.height = rect.h + "px";
+    cell.style.background = color(rect.node);
+    cell.textContent = rect.node.name;
+    cell.title = rect.node.name + "\n" + formatSize(rect.node.total) + "\n" + (rect.node.time || "");
+    if (rect.node.isDir && rect.node.children.length) {
+      cell.addEventListener("click", () => { mapPath.push(rect.node); renderMap(); });
+    }
+    map.appendChild(cell);
+  }
+}
+
+function show(view) {
+  document.getElementById("tree").hidden = view !== "tree";
+  document.getElementById("map-view").hidden = view !== "map";
+  if (view === "map") renderMap();
+}
+
+document.getElementById("show-tree").addEventListener("click", () => show("tree"));
+document.getElementById("show-map").addEventListener("click", () => show("map"));
+document.getElementById("sort").addEventListener("change", renderTree);
+window.addEventListener("resize", () => { if (!document.getElementById("map-view").hidden) renderMap(); });
+renderTree();
+</script>
+</body>
+</html>
+"##;
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn generate_tree_report_html(title: &str, tree: &TreeList) -> Result<String, Box<dyn Error>> {
+    // "</" can't appear inside a <script> element
+    let data = serde_json::to_string(tree)?.replace("</", "<\\/");
+    Ok(TREE_REPORT_TEMPLATE
+        .replace("{{TITLE}}", &escape_html(title))
+        .replace("{{DATA}}", &data))
+}
+
+#[test]
+fn test_generate_tree_report_html() {
+    let tree: TreeList = serde_json::from_str(r#"
+    [
+      {"type":"directory","name":"/Users/ojas/GDrive","size":400,"time":"2023-08-23","contents":[
+        {"type":"file","name":"</script><b>XÚÖ.gsite","size":157,"time":"2018-05-10"}
+      ]}
+    ]"#).unwrap();
+
+    let html = generate_tree_report_html("a<b & c", &tree).unwrap();
+
+    assert!(html.contains("<title>a&lt;b &amp; c</title>"));
+    assert!(html.contains("<\\/script><b>XÚÖ.gsite"));
+    assert_eq!(html.matches("</script>").count(), 2);
+}
+
+fn handle_tree_html_command(path: &Path, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let tree = load_tree(path)?;
+    let title = path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.display().to_string());
+    let html = generate_tree_report_html(&title, &tree)?;
+
+    match output {
+        Some(output_path) => {
+            fs::write(output_path, html)?;
+            println!("Tree report generated: {}", output_path.display());
+        }
+        None => io::stdout().write_all(html.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+// Bookmarks functionality
+
+#[derive(Debug, Clone, Serialize)]
+struct BookmarkEntry {
+    name: String,
+    href: String,
+    add_date: u64,
+    last_modified: u64,
+    // Size of the linked file when it was indexed, for matching renames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    // Keyword that opens the bookmark from the browser's address bar
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shortcut_url: Option<String>,
+    // Attributes we don't interpret, kept as read (ICON, ...)
+    #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "serialize_attributes")]
+    attributes: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct BookmarkFolder {
+    name: String,
+    last_modified: u64,
+    entries: Vec<BookmarkItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "serialize_attributes")]
+    attributes: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum BookmarkItem {
+    Link(BookmarkEntry),
+    Folder(BookmarkFolder),
+    Separator,
+}
+
+// Attributes are written as a JSON object, in file order
+fn serialize_attributes<S: serde::Serializer>(
+    attributes: &[(String, String)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(attributes.iter().map(|(key, value)| (key, value)))
+}
+
+fn system_time_to_unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn encode_path_preserving_slashes(path: &Path) -> String {
+    path.components()
+        .map(|component| {
+            let s = component.as_os_str().to_string_lossy();
+            urlencoding::encode(&s).to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn get_file_metadata(path: &Path) -> Result<(u64, u64), Box<dyn Error>> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?;
+    let created = metadata.created().unwrap_or(modified);
+
+    Ok((
+        system_time_to_unix_timestamp(created),
+        system_time_to_unix_timestamp(modified),
+    ))
+}
+
+// Which files a bookmark index picks up, besides skipping dotfiles and the
+// index itself. Globs match the file name or the path below the indexed folder
+#[derive(Default)]
+struct BookmarkScanFilter {
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+impl BookmarkScanFilter {
+    fn matches_any(patterns: &[glob::Pattern], name: &str, relative_path: &Path) -> bool {
+        patterns.iter().any(|p| p.matches(name) || p.matches_path(relative_path))
+    }
+
+    // Excludes apply to folders too; includes only to files
+    fn allows(&self, name: &str, relative_path: &Path, is_dir: bool) -> bool {
+        if Self::matches_any(&self.exclude, name, relative_path) {
+            return false;
+        }
+        is_dir || self.include.is_empty() || Self::matches_any(&self.include, name, relative_path)
+    }
+}
+
+// A .sbtignore in a folder (gitignore syntax) applies to it and everything below
+fn load_sbtignore(dir_path: &Path) -> Result<Option<Gitignore>, Box<dyn Error>> {
+    let ignore_path = dir_path.join(".sbtignore");
+    if !ignore_path.is_file() {
+        return Ok(None);
+    }
+    let mut builder = GitignoreBuilder::new(dir_path);
+    if let Some(err) = builder.add(&ignore_path) {
+        return Err(format!("{}: {}", ignore_path.display(), err).into());
+    }
+    Ok(Some(builder.build()?))
+}
+
+// The deepest .sbtignore with an opinion wins, so a subfolder can re-include with `!`
+fn is_sbtignored(ignores: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    ignores.iter().rev()
+        .map(|gitignore| gitignore.matched(path, is_dir))
+        .find(|m| !m.is_none())
+        .is_some_and(|m| m.is_ignore())
+}
+
+fn scan_directory(
+    dir_path: &Path,
+    base_path: &Path,
+    index_filename: &str,
+    recursive: bool,
+    filter: &BookmarkScanFilter,
+    ignores: &[Gitignore],
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>), Box<dyn Error>> {
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+
+    for entry in fs::read_dir(dir_path)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_name = entry.file_name();
+        let file_name_str = file_name.to_string_lossy();
+
+        // Skip the index file itself and its backups
+        if file_name_str == index_filename || file_name_str.starts_with(&format!("{}.bak.", index_filename)) {
+            continue;
+        }
+
+        // Skip hidden files (starting with .)
+        if file_name_str.starts_with('.') {
+            continue;
+        }
+
+        // Symlinked directories aren't followed, so a link back up the
+        // tree can't recurse forever
+        let is_dir = entry.file_type()?.is_dir();
+        let relative_path = path.strip_prefix(base_path).unwrap_or(&path);
+        if !filter.allows(&file_name_str, relative_path, is_dir) || is_sbtignored(ignores, &path, is_dir) {
+            continue;
+        }
+
+        if is_dir {
+            if recursive {
+                dirs.push(path);
+            }
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+
+    // Sort alphabetically
+    files.sort();
+    dirs.sort();
+
+    Ok((files, dirs))
+}
+
+struct ScannedDir {
+    path: PathBuf,
+    files: Vec<PathBuf>,
+    dirs: Vec<ScannedDir>,
+}
+
+// Scan a folder and, down to max_depth levels (None for unlimited), its subfolders
+fn scan_directory_tree(
+    dir_path: &Path,
+    index_filename: &str,
+    max_depth: Option<usize>,
+    filter: &BookmarkScanFilter,
+) -> Result<ScannedDir, Box<dyn Error>> {
+    scan_directory_level(dir_path, dir_path, index_filename, max_depth, filter, &mut Vec::new())
+}
+
+fn scan_directory_level(
+    dir_path: &Path,
+    base_path: &Path,
+    index_filename: &str,
+    max_depth: Option<usize>,
+    filter: &BookmarkScanFilter,
+    ignores: &mut Vec<Gitignore>,
+) -> Result<ScannedDir, Box<dyn Error>> {
+    let sbtignore = load_sbtignore(dir_path)?;
+    let pushed = sbtignore.is_some();
+    ignores.extend(sbtignore);
+
+    let descend = max_depth != Some(0);
+    let (files, dir_paths) = scan_directory(dir_path, base_path, index_filename, descend, filter, ignores)?;
+
+    let mut dirs = Vec::new();
+    for dir in &dir_paths {
+        dirs.push(scan_directory_level(dir, base_path, index_filename, max_depth.map(|d| d - 1), filter, ignores)?);
+    }
+
+    if pushed {
+        ignores.pop();
+    }
+    Ok(ScannedDir {
+        path: dir_path.to_path_buf(),
+        files,
+        dirs,
+    })
+}
+
+#[test]
+fn test_scan_directory_filters() {
+    let tmp = TestDir::new("scan-filter");
+    let dir = tmp.path();
+    fs::create_dir_all(dir.join("raw")).unwrap();
+    fs::create_dir_all(dir.join("edits")).unwrap();
+    for name in ["DSCF4462.JPG", "DSCF4462.RAF", "DSCF4462.JPG.xmp", "Thumbs.db", "raw/DSCF4463.JPG", "edits/DSCF4462.RAF", "edits/DSCF4464.JPG"] {
+        fs::write(dir.join(name), "x").unwrap();
+    }
+    fs::write(dir.join(".sbtignore"), "*.xmp\nThumbs.db\n*.RAF\n").unwrap();
+    // A subfolder can re-include what a parent ignores
+    fs::write(dir.join("edits/.sbtignore"), "!*.RAF\n").unwrap();
+
+    let names = |scanned: &ScannedDir| {
+        let mut names = Vec::new();
+        let mut stack = vec![scanned];
+        while let Some(d) = stack.pop() {
+            names.extend(d.files.iter().map(|f| f.strip_prefix(dir).unwrap().to_string_lossy().into_owned()));
+            stack.extend(d.dirs.iter());
+        }
+        names.sort();
+        names
+    };
+
+    let all = scan_directory_tree(dir, "index.html", None, &BookmarkScanFilter::default()).unwrap();
+    let filter = BookmarkScanFilter {
+        include: vec![glob::Pattern::new("*.JPG").unwrap()],
+        exclude: vec![glob::Pattern::new("raw").unwrap()],
+    };
+    let filtered = scan_directory_tree(dir, "index.html", None, &filter).unwrap();
+    let (all, filtered) = (names(&all), names(&filtered));
+
+    assert_eq!(all, ["DSCF4462.JPG", "edits/DSCF4462.RAF", "edits/DSCF4464.JPG", "raw/DSCF4463.JPG"]);
+    assert_eq!(filtered, ["DSCF4462.JPG", "edits/DSCF4464.JPG"]);
+}
+
+fn extract_title_from_bookmarks(content: &str) -> String {
+    parse_bookmark_document(content).0
+}
+
+fn parse_existing_bookmarks(content: &str) -> Vec<BookmarkItem> {
+    parse_bookmark_document(content).1
+}
+
+// Netscape bookmark parser
+//
+// Browser exports are only loosely HTML: <DT> and <p> are never closed,
+// attribute order and case vary, <DD> descriptions run until the next tag
+// that starts an entry and may span several lines. The parser tokenizes the
+// file and tracks open <DL> lists on a stack, so folders nest to any depth.
+// Attributes it doesn't interpret (ICON, TAGS, SHORTCUTURL, ...) are kept
+// verbatim and written back unchanged.
+
+struct HtmlTag {
+    name: String,
+    closing: bool,
+    attributes: Vec<(String, String)>,
+}
+
+enum HtmlToken<'a> {
+    Text(&'a str),
+    Tag(HtmlTag, &'a str),
+}
+
+fn parse_html_tag(raw: &str) -> Option<HtmlTag> {
+    let inner = raw.strip_prefix('<')?.strip_suffix('>')?;
+    let (closing, inner) = match inner.strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (false, inner),
+    };
+    let name_end = inner.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(inner.len());
+    let name = inner[..name_end].to_ascii_uppercase();
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut attributes = Vec::new();
+    let mut rest = inner[name_end..].trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+    while !rest.is_empty() {
+        let key_end = rest.find(|c: char| c.is_whitespace() || c == '=' || c == '/').unwrap_or(rest.len());
+        let key = rest[..key_end].to_ascii_uppercase();
+        rest = rest[key_end..].trim_start();
+
+        let mut value = String::new();
+        if let Some(after_eq) = rest.strip_prefix('=') {
+            let after_eq = after_eq.trim_start();
+            match after_eq.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let body = &after_eq[1..];
+                    let value_end = body.find(quote).unwrap_or(body.len());
+                    value = body[..value_end].to_string();
+                    rest = body.get(value_end + 1..).unwrap_or("");
+                }
+                _ => {
+                    let value_end = after_eq.find(char::is_whitespace).unwrap_or(after_eq.len());
+                    value = after_eq[..value_end].to_string();
+                    rest = &after_eq[value_end..];
+                }
+            }
+        }
+        if !key.is_empty() {
+            attributes.push((key, unescape_html(&value)));
+        }
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+    }
+
+    Some(HtmlTag { name, closing, attributes })
+}
+
+fn tokenize_html(content: &str) -> Vec<HtmlToken<'_>> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while let Some(offset) = content[pos..].find('<') {
+        let start = pos + offset;
+        if start > pos {
+            tokens.push(HtmlToken::Text(&content[pos..start]));
+        }
+
+        // Comments and the DOCTYPE carry nothing we need
+        if content[start..].starts_with("<!--") {
+            pos = content[start..].find("-->").map(|end| start + end + 3).unwrap_or(content.len());
+            continue;
+        }
+
+        // Find the end of the tag, skipping '>' inside quoted attribute values
+        let mut quote = None;
+        let mut end = None;
+        for (i, c) in content[start + 1..].char_indices() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '"' | '\'') => quote = Some(c),
+                (None, '>') => {
+                    end = Some(start + 1 + i + 1);
+                    break;
+                }
+                (None, '<') => break,
+                _ => {}
+            }
+        }
+
+        match end.and_then(|end| parse_html_tag(&content[start..end]).map(|tag| (tag, end))) {
+            Some((tag, end)) => {
+                if !tag.name.starts_with('!') {
+                    tokens.push(HtmlToken::Tag(tag, &content[start..end]));
+                }
+                pos = end;
+            }
+            None => {
+                // A stray '<' is just text
+                tokens.push(HtmlToken::Text(&content[start..start + 1]));
+                pos = start + 1;
+            }
+        }
+    }
+
+    if pos < content.len() {
+        tokens.push(HtmlToken::Text(&content[pos..]));
+    }
+    tokens
+}
+
+fn unescape_html(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(amp) = rest.find('&') {
+        result.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let decoded = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ => {
+                    let code = if let Some(hex) = entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                        u32::from_str_radix(hex, 16).ok()
+                    } else {
+                        entity.strip_prefix('#').and_then(|dec| dec.parse().ok())
+                    };
+                    code.and_then(char::from_u32)
+                }
+            };
+            c.map(|c| (c, end))
+        });
+
+        match decoded {
+            Some((c, end)) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+// "a, b,,a" -> ["a", "b"]
+fn parse_tags(text: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in text.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    tags
+}
+
+fn take_attribute(attributes: &mut Vec<(String, String)>, name: &str) -> Option<String> {
+    let index = attributes.iter().position(|(key, _)| key == name)?;
+    Some(attributes.remove(index).1)
+}
+
+// Collect the text of an element up to its closing tag, e.g. the name in <A>...</A>
+fn read_element_text(tokens: &[HtmlToken], i: &mut usize, closing_name: &str) -> String {
+    let mut text = String::new();
+    while *i < tokens.len() {
+        match &tokens[*i] {
+            HtmlToken::Tag(tag, _) if tag.closing && tag.name == closing_name => break,
+            HtmlToken::Tag(tag, _) if matches!(tag.name.as_str(), "DT" | "DL" | "DD" | "HR") => {
+                // Unclosed element; leave the structural tag for the caller
+                *i -= 1;
+                break;
+            }
+            HtmlToken::Text(t) => text.push_str(t),
+            HtmlToken::Tag(_, _) => {}
+        }
+        *i += 1;
+    }
+    unescape_html(text.trim())
+}
+
+// A <DD> runs until the next tag that starts or ends an entry
+fn read_description(tokens: &[HtmlToken], i: &mut usize) -> String {
+    let mut raw = String::new();
+    while *i + 1 < tokens.len() {
+        match &tokens[*i + 1] {
+            HtmlToken::Tag(tag, _) if matches!(tag.name.as_str(), "DT" | "DL" | "DD" | "HR") => break,
+            HtmlToken::Tag(tag, _) if tag.name == "P" => {}
+            HtmlToken::Tag(_, source) => raw.push_str(source),
+            HtmlToken::Text(t) => raw.push_str(t),
+        }
+        *i += 1;
+    }
+    unescape_html(raw.trim())
+}
+
+struct BookmarkFrame {
+    folder: Option<BookmarkFolder>,
+    items: Vec<BookmarkItem>,
+}
+
+fn parse_bookmark_document(content: &str) -> (String, Vec<BookmarkItem>) {
+    let tokens = tokenize_html(content);
+
+    let mut title = None;
+    let mut stack = vec![BookmarkFrame { folder: None, items: Vec::new() }];
+    let mut root_opened = false;
+    // A folder whose <H3> has been read but whose <DL> hasn't started yet
+    let mut pending_folder: Option<BookmarkFolder> = None;
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let HtmlToken::Tag(tag, _) = &tokens[i] else {
+            i += 1;
+            continue;
+        };
+
+        if !tag.closing && matches!(tag.name.as_str(), "DT" | "HR" | "A" | "H3") {
+            if let Some(folder) = pending_folder.take() {
+                stack.last_mut().unwrap().items.push(BookmarkItem::Folder(folder));
+            }
+        }
+
+        match (tag.name.as_str(), tag.closing) {
+            ("H1", false) => {
+                i += 1;
+                title = Some(read_element_text(&tokens, &mut i, "H1"));
+            }
+            ("A", false) => {
+                let mut attributes = tag.attributes.clone();
+                let href = take_attribute(&mut attributes, "HREF").unwrap_or_default();
+                let add_date = take_attribute(&mut attributes, "ADD_DATE").and_then(|d| d.parse().ok()).unwrap_or(0);
+                let last_modified = take_attribute(&mut attributes, "LAST_MODIFIED").and_then(|d| d.parse().ok()).unwrap_or(0);
+                i += 1;
+                let name = read_element_text(&tokens, &mut i, "A");
+                stack.last_mut().unwrap().items.push(BookmarkItem::Link(BookmarkEntry {
+                    name,
+                    href,
+                    add_date,
+                    last_modified,
+                    size: take_attribute(&mut attributes, "SIZE").and_then(|s| s.parse().ok()),
+                    description: None,
+                    tags: take_attribute(&mut attributes, "TAGS").map(|t| parse_tags(&t)).unwrap_or_default(),
+                    shortcut_url: take_attribute(&mut attributes, "SHORTCUTURL").filter(|k| !k.is_empty()),
+                    attributes,
+                }));
+            }
+            ("H3", false) => {
+                let mut attributes = tag.attributes.clone();
+                let last_modified = take_attribute(&mut attributes, "LAST_MODIFIED").and_then(|d| d.parse().ok()).unwrap_or(0);
+                i += 1;
+                let name = read_element_text(&tokens, &mut i, "H3");
+                pending_folder = Some(BookmarkFolder {
+                    name,
+                    last_modified,
+                    entries: Vec::new(),
+                    description: None,
+                    attributes,
+                });
+            }
+            ("HR", false) => stack.last_mut().unwrap().items.push(BookmarkItem::Separator),
+            ("DD", false) => {
+                let description = Some(read_description(&tokens, &mut i));
+                if let Some(folder) = pending_folder.as_mut() {
+                    folder.description = description;
+                } else {
+                    match stack.last_mut().unwrap().items.last_mut() {
+                        Some(BookmarkItem::Link(entry)) => entry.description = description,
+                        Some(BookmarkItem::Folder(folder)) => folder.description = description,
+                        _ => {}
+                    }
+                }
+            }
+            ("DL", false) => {
+                if let Some(folder) = pending_folder.take() {
+                    stack.push(BookmarkFrame { folder: Some(folder), items: Vec::new() });
+                } else if !root_opened {
+                    root_opened = true;
+                } else {
+                    // A list without a heading; its entries belong to the parent
+                    stack.push(BookmarkFrame { folder: None, items: Vec::new() });
+                }
+            }
+            ("DL", true) => {
+                if let Some(folder) = pending_folder.take() {
+                    stack.last_mut().unwrap().items.push(BookmarkItem::Folder(folder));
+                }
+                if stack.len() > 1 {
+                    close_bookmark_frame(&mut stack);
+                }
+            }
+            _ => {}
+        }
+
+        i += 1;
+    }
+
+    // Tolerate files that end without closing every list
+    if let Some(folder) = pending_folder.take() {
+        stack.last_mut().unwrap().items.push(BookmarkItem::Folder(folder));
+    }
+    while stack.len() > 1 {
+        close_bookmark_frame(&mut stack);
+    }
+
+    let items = stack.pop().map(|frame| frame.items).unwrap_or_default();
+    (title.unwrap_or_else(|| "Untitled".to_string()), items)
+}
+
+fn close_bookmark_frame(stack: &mut Vec<BookmarkFrame>) {
+    let frame = stack.pop().unwrap();
+    let parent = stack.last_mut().unwrap();
+    match frame.folder {
+        Some(mut folder) => {
+            folder.entries = frame.items;
+            parent.items.push(BookmarkItem::Folder(folder));
+        }
+        None => parent.items.extend(frame.items),
+    }
+}
+
+#[test]
+fn test_parse_browser_export() {
+    // Shaped like a Firefox export: mixed-case tags and attributes, extra
+    // attributes in any order, entities, multi-line descriptions and folders
+    // nested three deep
+    let data = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<!-- This is an automatically generated file. -->
+<META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks Menu</H1>
+
+<DL><p>
+    <DT><H3 ADD_DATE="1600000000" LAST_MODIFIED="1600000100" PERSONAL_TOOLBAR_FOLDER="true">Bookmarks Toolbar</H3>
+    <DD>Things I use
+    <DL><p>
+        <DT><A href="https://example.com/?a=1&amp;b=2" ICON="data:image/png;base64,AAA=" last_modified="1600000300" ADD_DATE="1600000200" TAGS="rust,cli" SHORTCUTURL="ex">Tom &amp; Jerry&#39;s &lt;site&gt;</A>
+        <DD>First line
+        second line
+        <DT><h3>Photos</h3>
+        <DL><p>
+            <DT><H3>XÚÖ</H3>
+            <DL><p>
+                <DT><A HREF="DSCF4462.JPG">DSCF4462.JPG</A>
+            </DL><p>
+        </DL><p>
+    </DL><p>
+    <HR>
+    <DT><A HREF="plain.html">Plain</A>
+</DL>
+"#;
+
+    let (title, items) = parse_bookmark_document(data);
+    assert_eq!(title, "Bookmarks Menu");
+    assert_eq!(items.len(), 3);
+
+    let BookmarkItem::Folder(toolbar) = &items[0] else { panic!("expected a folder") };
+    assert_eq!(toolbar.name, "Bookmarks Toolbar");
+    assert_eq!(toolbar.last_modified, 1600000100);
+    assert_eq!(toolbar.description.as_deref(), Some("Things I use"));
+    assert!(toolbar.attributes.contains(&("PERSONAL_TOOLBAR_FOLDER".to_string(), "true".to_string())));
+
+    let BookmarkItem::Link(link) = &toolbar.entries[0] else { panic!("expected a link") };
+    assert_eq!(link.href, "https://example.com/?a=1&b=2");
+    assert_eq!(link.name, "Tom & Jerry's <site>");
+    assert_eq!(link.add_date, 1600000200);
+    assert_eq!(link.last_modified, 1600000300);
+    assert_eq!(link.description.as_deref(), Some("First line\n        second line"));
+    assert_eq!(link.attributes[0].0, "ICON");
+    assert_eq!(link.tags, ["rust", "cli"]);
+    assert_eq!(link.shortcut_url.as_deref(), Some("ex"));
+
+    let BookmarkItem::Folder(photos) = &toolbar.entries[1] else { panic!("expected a folder") };
+    let BookmarkItem::Folder(nested) = &photos.entries[0] else { panic!("expected a folder") };
+    assert_eq!(nested.name, "XÚÖ");
+    assert_eq!(nested.entries.len(), 1);
+
+    assert!(matches!(items[1], BookmarkItem::Separator));
+
+    // Attributes we don't interpret are written back unchanged
+    let html = generate_bookmark_html(&title, &items);
+    assert!(html.contains("ICON=\"data:image/png;base64,AAA=\""));
+    assert!(html.contains("PERSONAL_TOOLBAR_FOLDER=\"true\""));
+    assert!(html.contains("SHORTCUTURL=\"ex\""));
+}
+
+fn generate_bookmark_html(
+    folder_name: &str,
+    items: &[BookmarkItem],
+) -> String {
+    let mut html = String::new();
+
+    html.push_str("<!DOCTYPE NETSCAPE-Bookmark-file-1>\n");
+    html.push_str("<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n");
+    html.push_str("<!-- This is an automatically generated file. It will be read and modified by automated tools. Edit only if you understand the risks -->\n");
+    html.push_str("<TITLE>Bookmarks</TITLE>\n");
+    html.push_str(&format!("<H1>{}</H1>\n", escape_html(folder_name)));
+    html.push_str("<DL><p>\n");
+    push_bookmark_items(&mut html, items, 1);
+    html.push_str("</DL><p>\n");
+    html
+}
+
+fn push_bookmark_items(html: &mut String, items: &[BookmarkItem], depth: usize) {
+    let indent = "    ".repeat(depth);
+
+    for item in items {
+        match item {
+            BookmarkItem::Link(entry) => {
+                let mut attributes = Vec::new();
+                if let Some(size) = entry.size {
+                    attributes.push(("SIZE".to_string(), size.to_string()));
+                }
+                if let Some(keyword) = &entry.shortcut_url {
+                    attributes.push(("SHORTCUTURL".to_string(), keyword.clone()));
+                }
+                if !entry.tags.is_empty() {
+                    attributes.push(("TAGS".to_string(), entry.tags.join(",")));
+                }
+                attributes.extend(entry.attributes.iter().cloned());
+                html.push_str(&format!(
+                    "{}<DT><A HREF=\"{}\" ADD_DATE=\"{}\" LAST_MODIFIED=\"{}\"{}>{}</A>\n",
+                    indent, escape_html(&entry.href), entry.add_date, entry.last_modified,
+                    format_extra_attributes(&attributes), escape_html(&entry.name)
+                ));
+                if let Some(desc) = &entry.description {
+                    html.push_str(&format!("{}<DD>{}\n", indent, escape_description(desc)));
+                }
+            }
+            BookmarkItem::Folder(folder) => {
+                html.push_str(&format!(
+                    "{}<DT><H3 LAST_MODIFIED=\"{}\"{}>{}</H3>\n",
+                    indent, folder.last_modified,
+                    format_extra_attributes(&folder.attributes), escape_html(&folder.name)
+                ));
+                if let Some(desc) = &folder.description {
+                    html.push_str(&format!("{}<DD>{}\n", indent, escape_description(desc)));
+                }
+                html.push_str(&format!("{}<DL><p>\n", indent));
+                push_bookmark_items(html, &folder.entries, depth + 1);
+                html.push_str(&format!("{}</DL><p>\n", indent));
+            }
+            BookmarkItem::Separator => {
+                html.push_str(&format!("{}<HR>\n", indent));
+            }
+        }
+    }
+}
+
+// Descriptions may carry markup like <br>, so only escape what can't be
+// read back: a bare '&' and a '<' that doesn't start a tag
+fn escape_description(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for (i, c) in text.char_indices() {
+        let rest = &text[i + c.len_utf8()..];
+        match c {
+            '&' if !starts_with_entity(rest) => result.push_str("&amp;"),
+            '<' if !rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!') => {
+                result.push_str("&lt;")
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn starts_with_entity(text: &str) -> bool {
+    let Some(end) = text.find(';').filter(|&end| end > 0 && end <= 10) else { return false };
+    let entity = &text[..end];
+    match entity.strip_prefix('#') {
+        Some(code) => match code.strip_prefix(['x', 'X']) {
+            Some(hex) => !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()),
+            None => !code.is_empty() && code.chars().all(|c| c.is_ascii_digit()),
+        },
+        None => entity.chars().all(|c| c.is_ascii_alphanumeric()),
+    }
+}
+
+fn format_extra_attributes(attributes: &[(String, String)]) -> String {
+    attributes.iter()
+        .map(|(key, value)| format!(" {}=\"{}\"", key, escape_html(value)))
+        .collect()
+}
+
+#[test]
+fn test_bookmark_html_round_trip() {
+    let link = |name: &str, description: Option<&str>| BookmarkItem::Link(BookmarkEntry {
+        name: name.to_string(),
+        href: encode_path_preserving_slashes(Path::new(name)),
+        add_date: 1766354780,
+        last_modified: 1766354828,
+        size: None,
+        description: description.map(str::to_string),
+        tags: vec!["a&b".to_string(), "\"c\"".to_string()],
+        shortcut_url: None,
+        attributes: vec![("ICON_URI".to_string(), "https://example.com/?a=1&b=\"2\"".to_string())],
+    });
+    let items = vec![
+        link("a<b&c.jpg", Some("Tom & Jerry <3")),
+        BookmarkItem::Folder(BookmarkFolder {
+            name: "XÚÖ & <friends>".to_string(),
+            last_modified: 1,
+            entries: vec![link("XÚÖ \"quoted\".jpg", Some("Ünïcödé caption: 1 < 2 > 0"))],
+            description: Some("R&D".to_string()),
+            attributes: Vec::new(),
+        }),
+        link("beach.jpg", Some("Day one<br>Sunset at <a href=\"https://example.com/?a=1&b=2\">Playa</a>")),
+        BookmarkItem::Link(BookmarkEntry {
+            name: "Search".to_string(),
+            href: "https://example.com/?q=a&lang=de".to_string(),
+            add_date: 0,
+            last_modified: 0,
+            size: None,
+            description: None,
+            tags: Vec::new(),
+            shortcut_url: None,
+            attributes: Vec::new(),
+        }),
+    ];
+
+    let html = generate_bookmark_html("Photos & <XÚÖ>", &items);
+    assert!(html.contains("<H1>Photos &amp; &lt;XÚÖ&gt;</H1>"));
+    assert!(html.contains(">a&lt;b&amp;c.jpg</A>"));
+    assert!(html.contains("HREF=\"https://example.com/?q=a&amp;lang=de\""));
+    assert!(html.contains("<DD>Tom &amp; Jerry &lt;3\n"));
+    assert!(html.contains("<DD>Day one<br>Sunset at <a href=\"https://example.com/?a=1&amp;b=2\">Playa</a>\n"));
+
+    let (title, parsed) = parse_bookmark_document(&html);
+    assert_eq!(title, "Photos & <XÚÖ>");
+    assert_eq!(generate_bookmark_html(&title, &parsed), html);
+
+    let BookmarkItem::Link(first) = &parsed[0] else { panic!("expected a link") };
+    assert_eq!(first.name, "a<b&c.jpg");
+    assert_eq!(first.href, "a%3Cb%26c.jpg");
+    assert_eq!(first.description.as_deref(), Some("Tom & Jerry <3"));
+    assert_eq!(first.tags, ["a&b", "\"c\""]);
+    assert_eq!(first.attributes[0].1, "https://example.com/?a=1&b=\"2\"");
+    let BookmarkItem::Folder(folder) = &parsed[1] else { panic!("expected a folder") };
+    assert_eq!(folder.name, "XÚÖ & <friends>");
+    assert_eq!(folder.description.as_deref(), Some("R&D"));
+    let BookmarkItem::Link(nested) = &folder.entries[0] else { panic!("expected a link") };
+    assert_eq!(nested.name, "XÚÖ \"quoted\".jpg");
+    assert_eq!(nested.description.as_deref(), Some("Ünïcödé caption: 1 < 2 > 0"));
+    let BookmarkItem::Link(beach) = &parsed[2] else { panic!("expected a link") };
+    assert_eq!(beach.description.as_deref(), Some("Day one<br>Sunset at <a href=\"https://example.com/?a=1&b=2\">Playa</a>"));
+    let BookmarkItem::Link(url) = &parsed[3] else { panic!("expected a link") };
+    assert_eq!(url.href, "https://example.com/?q=a&lang=de");
+}
+
+// The file a bookmark points at, for relative links; None for URLs
+fn local_bookmark_path(href: &str, base_path: &Path) -> Option<PathBuf> {
+    let before_slash = href.split('/').next().unwrap_or("");
+    if href.is_empty() || href.starts_with('#') || before_slash.contains(':') {
+        return None;
+    }
+    // A literal ? or # in a file name is percent-encoded, so these start the
+    // query and fragment
+    let path = href.split(['?', '#']).next().unwrap_or("");
+    let decoded = urlencoding::decode(path).ok()?;
+    Some(base_path.join(decoded.as_ref()))
+}
+
+// Add or remove "missing" in an entry's tags
+fn set_missing_tag(tags: &mut Vec<String>, missing: bool) {
+    let has_tag = tags.iter().any(|t| t == "missing");
+    if missing && !has_tag {
+        tags.push("missing".to_string());
+    } else if !missing && has_tag {
+        tags.retain(|t| t != "missing");
+    }
+}
+
+// Image metadata
+
+fn parse_exif_datetime(value: &str) -> Option<u64> {
+    let datetime = chrono::NaiveDateTime::parse_from_str(value.trim_end_matches('\0').trim(), "%Y:%m:%d %H:%M:%S").ok()?;
+    Some(datetime.and_utc().timestamp().max(0) as u64)
+}
+
+fn read_exif(path: &Path) -> Option<exif::Exif> {
+    let file = File::open(path).ok()?;
+    exif::Reader::new().read_from_container(&mut BufReader::new(file)).ok()
+}
+
+fn exif_ascii(exif: &exif::Exif, tag: exif::Tag) -> Option<String> {
+    match &exif.get_field(tag, exif::In::PRIMARY)?.value {
+        exif::Value::Ascii(values) => values.first()
+            .map(|v| String::from_utf8_lossy(v).trim_end_matches('\0').trim().to_string())
+            .filter(|v| !v.is_empty()),
+        _ => None,
+    }
+}
+
+// Capture date from EXIF DateTimeOriginal, falling back to DateTime.
+// Camera clocks have no time zone, so the value is taken as UTC
+fn exif_capture_time(exif: &exif::Exif) -> Option<u64> {
+    [exif::Tag::DateTimeOriginal, exif::Tag::DateTime].iter()
+        .find_map(|tag| exif_ascii(exif, *tag).and_then(|v| parse_exif_datetime(&v)))
+}
+
+fn read_exif_capture_time(path: &Path) -> Option<u64> {
+    read_exif(path).as_ref().and_then(exif_capture_time)
+}
+
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "tif", "tiff", "png", "webp", "heic", "heif", "dng"];
+
+fn is_image_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+}
+
+// XMP packets and JPEG APP segments sit near the start of the file
+const IMAGE_METADATA_HEAD: u64 = 1024 * 1024;
+
+static XMP_DESCRIPTION_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<dc:description>(.*?)</dc:description>").unwrap());
+static XMP_LIST_ITEM_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<rdf:li[^>]*>(.*?)</rdf:li>").unwrap());
+
+fn read_xmp_description(data: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(data);
+    let start = text.find("<x:xmpmeta")?;
+    let end = text[start..].find("</x:xmpmeta>").map(|e| start + e).unwrap_or(text.len());
+    // Only look inside the description, so an empty one doesn't pick up
+    // the next element's list (dc:subject keywords, say)
+    let description = XMP_DESCRIPTION_RE.captures(&text[start..end])?;
+    XMP_LIST_ITEM_RE.captures(description.get(1)?.as_str())
+        .map(|caps| unescape_html(caps[1].trim()))
+        .filter(|d| !d.is_empty())
+}
+
+// The payloads of a JPEG's header segments, up to the image data
+fn jpeg_segments(data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = Vec::new();
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return segments;
+    }
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if len < 2 || pos + 2 + len > data.len() {
+            break;
+        }
+        segments.push((marker, &data[pos + 4..pos + 2 + len]));
+        pos += 2 + len;
+    }
+    segments
+}
+
+// IPTC Caption-Abstract (2:120), stored in a JPEG's Photoshop APP13 segment
+fn read_iptc_caption(data: &[u8]) -> Option<String> {
+    let (_, app13) = jpeg_segments(data).into_iter()
+        .find(|(marker, payload)| *marker == 0xED && payload.starts_with(b"Photoshop 3.0\0"))?;
+
+    // Image resource blocks: "8BIM", id, padded Pascal name, size, padded data
+    let mut pos = 14;
+    let mut iptc = None;
+    while pos + 8 <= app13.len() && &app13[pos..pos + 4] == b"8BIM" {
+        let id = u16::from_be_bytes([app13[pos + 4], app13[pos + 5]]);
+        let name_len = app13[pos + 6] as usize;
+        pos += 6 + (name_len + 2) / 2 * 2;
+        let size = u32::from_be_bytes(app13.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let block = app13.get(pos + 4..pos + 4 + size)?;
+        if id == 0x0404 {
+            iptc = Some(block);
+            break;
+        }
+        pos += 4 + size + size % 2;
+    }
+
+    let iptc = iptc?;
+    let mut pos = 0;
+    while pos + 5 <= iptc.len() && iptc[pos] == 0x1C {
+        let (record, dataset) = (iptc[pos + 1], iptc[pos + 2]);
+        let len = u16::from_be_bytes([iptc[pos + 3], iptc[pos + 4]]) as usize;
+        let value = iptc.get(pos + 5..pos + 5 + len)?;
+        if record == 2 && dataset == 120 {
+            // UTF-8 in practice; older files may be Latin-1
+            let caption = String::from_utf8(value.to_vec())
+                .unwrap_or_else(|_| value.iter().map(|&b| b as char).collect());
+            return Some(caption.trim().to_string()).filter(|c| !c.is_empty());
+        }
+        pos += 5 + len;
+    }
+    None
+}
+
+struct ImageMetadata {
+    description: Option<String>,
+    capture_time: Option<u64>,
+}
+
+// The description comes from XMP dc:description, IPTC Caption-Abstract or
+// EXIF ImageDescription, in that order
+fn read_image_metadata(path: &Path) -> ImageMetadata {
+    let mut head = Vec::new();
+    if let Ok(file) = File::open(path) {
+        let _ = io::Read::read_to_end(&mut io::Read::take(file, IMAGE_METADATA_HEAD), &mut head);
+    }
+    let exif = read_exif(path);
+
+    let description = read_xmp_description(&head)
+        .or_else(|| read_iptc_caption(&head))
+        .or_else(|| exif.as_ref().and_then(|e| exif_ascii(e, exif::Tag::ImageDescription)));
+
+    ImageMetadata {
+        description,
+        capture_time: exif.as_ref().and_then(exif_capture_time),
+    }
+}
+
+#[test]
+fn test_read_embedded_captions() {
+    let xmp = r#"<?xpacket begin=""?><x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description>
+<dc:description><rdf:Alt><rdf:li xml:lang="x-default">Nakamichi &amp; XÚÖ</rdf:li></rdf:Alt></dc:description>
+</rdf:Description></rdf:RDF></x:xmpmeta>"#.as_bytes();
+    assert_eq!(read_xmp_description(xmp).as_deref(), Some("Nakamichi & XÚÖ"));
+    let keywords_only = r#"<x:xmpmeta><rdf:Description><dc:description><rdf:Alt/></dc:description>
+<dc:subject><rdf:Bag><rdf:li>speakers</rdf:li></rdf:Bag></dc:subject></rdf:Description></x:xmpmeta>"#.as_bytes();
+    assert_eq!(read_xmp_description(keywords_only), None);
+
+    // A JPEG header with an APP13 segment holding one IPTC caption
+    let caption = "Left speaker".as_bytes();
+    let mut iptc = vec![0x1C, 2, 120];
+    iptc.extend((caption.len() as u16).to_be_bytes());
+    iptc.extend(caption);
+    let mut app13 = b"Photoshop 3.0\08BIM\x04\x04\0\0".to_vec();
+    app13.extend((iptc.len() as u32).to_be_bytes());
+    app13.extend(&iptc);
+    let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xED];
+    jpeg.extend(((app13.len() + 2) as u16).to_be_bytes());
+    jpeg.extend(&app13);
+    jpeg.extend([0xFF, 0xD9]);
+    assert_eq!(read_iptc_caption(&jpeg).as_deref(), Some("Left speaker"));
+
+    assert_eq!(read_iptc_caption(xmp), None);
+}
+
+// Rename detection
+//
+// A renamed or moved file keeps its size and mtime, so an existing link whose
+// file is gone is matched to a new file with the same size and mtime, provided
+// neither side has another candidate. Links from indexes that predate recorded
+// sizes are matched on mtime alone. The old entry's caption, add date and attributes move
+// to the new href; its name follows the file unless it was edited by hand.
+
+fn collect_bookmark_hrefs(items: &[BookmarkItem], hrefs: &mut HashSet<String>) {
+    for item in items {
+        match item {
+            BookmarkItem::Link(entry) => {
+                hrefs.insert(entry.href.clone());
+            }
+            BookmarkItem::Folder(folder) => collect_bookmark_hrefs(&folder.entries, hrefs),
+            BookmarkItem::Separator => {}
+        }
+    }
+}
+
+// A file's href, size and mtime
+type RenameCandidate = (String, Option<u64>, u64);
+
+fn collect_missing_bookmarks(items: &[BookmarkItem], base_path: &Path, missing: &mut Vec<RenameCandidate>) {
+    for item in items {
+        match item {
+            BookmarkItem::Link(entry) => {
+                if local_bookmark_path(&entry.href, base_path).is_some_and(|path| !path.exists()) {
+                    missing.push((entry.href.clone(), entry.size, entry.last_modified));
+                }
+            }
+            BookmarkItem::Folder(folder) => collect_missing_bookmarks(&folder.entries, base_path, missing),
+            BookmarkItem::Separator => {}
+        }
+    }
+}
+
+fn collect_new_files(
+    dir: &ScannedDir,
+    base_path: &Path,
+    known_hrefs: &HashSet<String>,
+    new_files: &mut Vec<RenameCandidate>,
+) -> Result<(), Box<dyn Error>> {
+    for file_path in &dir.files {
+        let relative_path = file_path.strip_prefix(base_path).unwrap_or(file_path);
+        let href = encode_path_preserving_slashes(relative_path);
+        if !known_hrefs.contains(&href) {
+            let (_, last_modified) = get_file_metadata(file_path)?;
+            new_files.push((href, Some(fs::metadata(file_path)?.len()), last_modified));
+        }
+    }
+    for subdir in &dir.dirs {
+        collect_new_files(subdir, base_path, known_hrefs, new_files)?;
+    }
+    Ok(())
+}
+
+fn href_parent(href: &str) -> &str {
+    href.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+}
+
+fn href_file_name(href: &str) -> String {
+    let name = href.rsplit('/').next().unwrap_or(href);
+    urlencoding::decode(name).map(|n| n.into_owned()).unwrap_or_else(|_| name.to_string())
+}
+
+#[derive(Default)]
+struct DetectedRenames {
+    // Old href to new href for every unambiguous rename
+    renames: HashMap<String, String>,
+    // Entries from before sizes were recorded can only be matched on mtime,
+    // which burst shots share, so these are reported but not carried over
+    possible: Vec<(String, String)>,
+}
+
+fn detect_renames(
+    items: &[BookmarkItem],
+    dir: &ScannedDir,
+    base_path: &Path,
+) -> Result<DetectedRenames, Box<dyn Error>> {
+    let mut known_hrefs = HashSet::new();
+    collect_bookmark_hrefs(items, &mut known_hrefs);
+
+    let mut missing = Vec::new();
+    collect_missing_bookmarks(items, base_path, &mut missing);
+    if missing.is_empty() {
+        return Ok(DetectedRenames::default());
+    }
+    let mut new_files = Vec::new();
+    collect_new_files(dir, base_path, &known_hrefs, &mut new_files)?;
+
+    let matches = |(_, old_size, old_mtime): &RenameCandidate, (_, new_size, new_mtime): &RenameCandidate| {
+        old_mtime == new_mtime && old_size.is_some() && old_size == new_size
+    };
+
+    let mut detected = DetectedRenames::default();
+    for old in &missing {
+        let mut candidates = new_files.iter().filter(|new| matches(old, new));
+        let (Some(new), None) = (candidates.next(), candidates.next()) else { continue };
+        if missing.iter().filter(|other| matches(other, new)).count() == 1 {
+            detected.renames.insert(old.0.clone(), new.0.clone());
+        }
+    }
+
+    let claimed: HashSet<&String> = detected.renames.values().collect();
+    for (old_href, _, old_mtime) in missing.iter().filter(|(_, size, _)| size.is_none()) {
+        for (new_href, _, _) in new_files.iter().filter(|(href, _, mtime)| mtime == old_mtime && !claimed.contains(href)) {
+            detected.possible.push((old_href.clone(), new_href.clone()));
+        }
+    }
+    Ok(detected)
+}
+
+// Renames within a folder are done in place; entries that moved to another
+// folder are taken out and returned keyed by their new href
+fn apply_renames(
+    items: &mut Vec<BookmarkItem>,
+    renames: &HashMap<String, String>,
+    moved: &mut HashMap<String, BookmarkEntry>,
+) {
+    for mut item in std::mem::take(items) {
+        match &mut item {
+            BookmarkItem::Link(entry) => {
+                if let Some(new_href) = renames.get(&entry.href) {
+                    if entry.name == href_file_name(&entry.href) {
+                        entry.name = href_file_name(new_href);
+                    }
+                    let same_folder = href_parent(&entry.href) == href_parent(new_href);
+                    entry.href = new_href.clone();
+                    if !same_folder {
+                        moved.insert(new_href.clone(), entry.clone());
+                        continue;
+                    }
+                }
+            }
+            BookmarkItem::Folder(folder) => apply_renames(&mut folder.entries, renames, moved),
+            BookmarkItem::Separator => {}
+        }
+        items.push(item);
+    }
+}
+
+// Bookmark sorting
+//
+// Links sort before folders. Separators split a list into sections that are
+// sorted on their own, so hand-made groupings survive a full sort.
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum NameChunk {
+    Number(u64),
+    Text(String),
+}
+
+// "IMG_10.jpg" -> [Text("img_"), Number(10), Text(".jpg")]
+fn natural_name_key(name: &str) -> Vec<NameChunk> {
+    let mut chunks = Vec::new();
+    let mut rest = name;
+    while let Some(c) = rest.chars().next() {
+        let is_digit = c.is_ascii_digit();
+        let end = rest.find(|c: char| c.is_ascii_digit() != is_digit).unwrap_or(rest.len());
+        let run = &rest[..end];
+        match run.parse() {
+            Ok(number) if is_digit => chunks.push(NameChunk::Number(number)),
+            _ => chunks.push(NameChunk::Text(run.to_lowercase())),
+        }
+        rest = &rest[end..];
+    }
+    chunks
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct BookmarkSortKey {
+    is_folder: bool,
+    value: u64,
+    name: Vec<NameChunk>,
+    raw_name: String,
+}
+
+fn bookmark_sort_key(item: &BookmarkItem, sort: BookmarkSort, base_path: &Path) -> Option<BookmarkSortKey> {
+    let (is_folder, name, last_modified, href) = match item {
+        BookmarkItem::Link(entry) => (false, &entry.name, entry.last_modified, Some(&entry.href)),
+        BookmarkItem::Folder(folder) => (true, &folder.name, folder.last_modified, None),
+        BookmarkItem::Separator => return None,
+    };
+    let path = href.and_then(|href| local_bookmark_path(href, base_path));
+
+    let value = match sort {
+        BookmarkSort::Name | BookmarkSort::Natural => 0,
+        BookmarkSort::Mtime => last_modified,
+        BookmarkSort::Exif => path.as_deref().and_then(read_exif_capture_time).unwrap_or(last_modified),
+        BookmarkSort::Size => path.and_then(|p| fs::metadata(p).ok()).map(|m| m.len()).unwrap_or(0),
+    };
+    let name_key = match sort {
+        BookmarkSort::Name => vec![NameChunk::Text(name.clone())],
+        _ => natural_name_key(name),
+    };
+
+    Some(BookmarkSortKey { is_folder, value, name: name_key, raw_name: name.clone() })
+}
+
+fn sort_bookmarks(items: Vec<BookmarkItem>, sort: BookmarkSort, base_path: &Path) -> Vec<BookmarkItem> {
+    let mut sorted = Vec::with_capacity(items.len());
+    let mut section = Vec::new();
+
+    for item in items {
+        match bookmark_sort_key(&item, sort, base_path) {
+            Some(key) => section.push((key, item)),
+            None => {
+                section.sort_by(|a, b| a.0.cmp(&b.0));
+                sorted.extend(section.drain(..).map(|(_, item)| item));
+                sorted.push(item);
+            }
+        }
+    }
+    section.sort_by(|a, b| a.0.cmp(&b.0));
+    sorted.extend(section.into_iter().map(|(_, item)| item));
+    sorted
+}
+
+// Keep the first existing_count items where they are and insert each of
+// the rest before the first item that sorts after it
+fn insert_sorted_bookmarks(
+    mut items: Vec<BookmarkItem>,
+    existing_count: usize,
+    sort: BookmarkSort,
+    base_path: &Path,
+) -> Vec<BookmarkItem> {
+    let new_items = items.split_off(existing_count);
+    let mut keyed: Vec<_> = items.into_iter()
+        .map(|item| (bookmark_sort_key(&item, sort, base_path), item))
+        .collect();
+    let mut new_keyed: Vec<_> = new_items.into_iter()
+        .map(|item| (bookmark_sort_key(&item, sort, base_path), item))
+        .collect();
+    new_keyed.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (key, item) in new_keyed {
+        let position = keyed.iter()
+            .position(|(existing, _)| existing.is_some() && *existing > key)
+            .unwrap_or(keyed.len());
+        keyed.insert(position, (key, item));
+    }
+    keyed.into_iter().map(|(_, item)| item).collect()
+}
+
+// Both merges match folders by name and links by normalized URL, so a
+// rescan and an import place and dedupe entries the same way
+fn find_bookmark_folder_mut<'a>(items: &'a mut [BookmarkItem], name: &str) -> Option<&'a mut BookmarkFolder> {
+    items.iter_mut().find_map(|item| match item {
+        BookmarkItem::Folder(folder) if folder.name == name => Some(folder),
+        _ => None,
+    })
+}
+
+#[derive(Default)]
+struct BookmarkMergeOptions {
+    sync: SyncPolicy,
+    sort: Option<BookmarkSort>,
+    preserve_order: bool,
+    // Fill in captions and add dates of new images from embedded metadata
+    image_metadata: bool,
+}
+
+fn merge_bookmarks(
+    existing: Vec<BookmarkItem>,
+    dir: &ScannedDir,
+    base_path: &Path,
+    options: &BookmarkMergeOptions,
+    moved: &mut HashMap<String, BookmarkEntry>,
+) -> Result<Vec<BookmarkItem>, Box<dyn Error>> {
+    let sync = options.sync;
+    let mut items = Vec::new();
+    let mut existing_file_hrefs = HashSet::new();
+    let scanned_dir_names: HashSet<&str> = dir.dirs.iter()
+        .filter_map(|d| d.path.file_name().and_then(|n| n.to_str()))
+        .collect();
+
+    // Collect existing entries and preserve them, applying the sync policy
+    // to links whose file is gone
+    for mut item in existing {
+        match &mut item {
+            BookmarkItem::Link(entry) => {
+                if let Some(path) = local_bookmark_path(&entry.href, base_path) {
+                    let missing = !path.exists();
+                    if missing && sync == SyncPolicy::Remove {
+                        continue;
+                    }
+                    if sync != SyncPolicy::Keep {
+                        set_missing_tag(&mut entry.tags, missing);
+                    }
+                    // Indexes written before sizes were recorded pick them up here
+                    if entry.size.is_none() && !missing {
+                        entry.size = fs::metadata(&path).ok().map(|m| m.len());
+                    }
+                }
+                existing_file_hrefs.insert(normalize_bookmark_url(&entry.href));
+            }
+            BookmarkItem::Folder(folder) => {
+                // A folder whose directory is gone won't be merged below,
+                // so its entries are checked here
+                let folder_path = dir.path.join(&folder.name);
+                if sync != SyncPolicy::Keep && !scanned_dir_names.contains(folder.name.as_str()) && !folder_path.exists() {
+                    let gone = ScannedDir { path: folder_path, files: Vec::new(), dirs: Vec::new() };
+                    let entries = std::mem::take(&mut folder.entries);
+                    folder.entries = merge_bookmarks(entries, &gone, base_path, options, moved)?;
+                    if sync == SyncPolicy::Remove && folder.entries.is_empty() {
+                        continue;
+                    }
+                }
+            }
+            BookmarkItem::Separator => {}
+        }
+        items.push(item);
+    }
+    let existing_count = items.len();
+
+    // Add new file entries, linked relative to the index file
+    for file_path in &dir.files {
+        let file_name = file_path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("");
+        let relative_path = file_path.strip_prefix(base_path)
+            .unwrap_or(file_path);
+        let encoded_path = encode_path_preserving_slashes(relative_path);
+
+        if existing_file_hrefs.contains(&normalize_bookmark_url(&encoded_path)) {
+            continue;
+        }
+        if let Some(mut entry) = moved.remove(&encoded_path) {
+            if entry.size.is_none() {
+                entry.size = Some(fs::metadata(file_path)?.len());
+            }
+            items.push(BookmarkItem::Link(entry));
+        } else {
+            let (mut add_date, last_modified) = get_file_metadata(file_path)?;
+            let mut description = None;
+            if options.image_metadata && is_image_file(file_path) {
+                let metadata = read_image_metadata(file_path);
+                add_date = metadata.capture_time.unwrap_or(add_date);
+                description = metadata.description;
+            }
+            items.push(BookmarkItem::Link(BookmarkEntry {
+                name: file_name.to_string(),
+                href: encoded_path,
+                add_date,
+                last_modified,
+                size: Some(fs::metadata(file_path)?.len()),
+                description,
+                tags: Vec::new(),
+                shortcut_url: None,
+                attributes: Vec::new(),
+            }));
+        }
+    }
+
+    // Add or update subfolders, merging their contents at every level
+    for subdir in &dir.dirs {
+        let dir_name = subdir.path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("");
+
+        if let Some(folder) = find_bookmark_folder_mut(&mut items, dir_name) {
+            let entries = std::mem::take(&mut folder.entries);
+            folder.entries = merge_bookmarks(entries, subdir, base_path, options, moved)?;
+        } else {
+            let (_, last_modified) = get_file_metadata(&subdir.path)?;
+            items.push(BookmarkItem::Folder(BookmarkFolder {
+                name: dir_name.to_string(),
+                last_modified,
+                entries: merge_bookmarks(Vec::new(), subdir, base_path, options, moved)?,
+                description: None,
+                attributes: Vec::new(),
+            }));
+        }
+    }
+
+    match options.sort {
+        Some(sort) if options.preserve_order => {
+            Ok(insert_sorted_bookmarks(items, existing_count, sort, base_path))
+        }
+        Some(sort) => Ok(sort_bookmarks(items, sort, base_path)),
+        None => Ok(items),
+    }
+}
+
+#[test]
+fn test_merge_bookmarks_nested() {
+    let tmp = TestDir::new("bookmarks");
+    let dir = tmp.path();
+    fs::create_dir_all(dir.join("2025/XÚÖ/raw")).unwrap();
+    fs::write(dir.join("top.jpg"), "a").unwrap();
+    fs::write(dir.join("2025/XÚÖ/DSCF4462.JPG"), "b").unwrap();
+    fs::write(dir.join("2025/XÚÖ/raw/DSCF4462.RAF"), "c").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("../..", dir.join("2025/XÚÖ/raw/loop")).unwrap();
+
+    // An existing caption two levels down survives the merge
+    let existing = parse_existing_bookmarks(r#"<DL><p>
+    <DT><H3>2025</H3>
+    <DL><p>
+        <DT><H3>XÚÖ</H3>
+        <DL><p>
+            <DT><A HREF="2025/X%C3%9A%C3%96/DSCF4462.JPG">DSCF4462.JPG</A>
+            <DD>Left speaker
+        </DL><p>
+    </DL><p>
+</DL><p>"#);
+
+    let scanned = scan_directory_tree(dir, "index.html", None, &BookmarkScanFilter::default()).unwrap();
+    let items = merge_bookmarks(existing, &scanned, dir, &BookmarkMergeOptions::default(), &mut HashMap::new()).unwrap();
+    let shallow = scan_directory_tree(dir, "index.html", Some(1), &BookmarkScanFilter::default()).unwrap();
+
+    let BookmarkItem::Folder(year) = &items[0] else { panic!("expected a folder") };
+    let BookmarkItem::Folder(album) = &year.entries[0] else { panic!("expected a folder") };
+    assert_eq!(album.entries.len(), 2);
+    let BookmarkItem::Link(photo) = &album.entries[0] else { panic!("expected a link") };
+    assert_eq!(photo.description.as_deref(), Some("Left speaker"));
+    let BookmarkItem::Folder(raw) = &album.entries[1] else { panic!("expected a folder") };
+    assert_eq!(raw.entries.len(), 1);
+    let BookmarkItem::Link(raw_file) = &raw.entries[0] else { panic!("expected a link") };
+    assert_eq!(raw_file.href, "2025/X%C3%9A%C3%96/raw/DSCF4462.RAF");
+    assert!(matches!(items[1], BookmarkItem::Link(_)));
+
+    assert_eq!(shallow.dirs.len(), 1);
+    assert!(shallow.dirs[0].dirs.is_empty());
+}
+
+#[test]
+fn test_sort_bookmarks() {
+    let link = |name: &str| BookmarkItem::Link(BookmarkEntry {
+        name: name.to_string(),
+        href: format!("https://example.com/{}", name),
+        add_date: 0,
+        last_modified: 0,
+        size: None,
+        description: None,
+        tags: Vec::new(),
+        shortcut_url: None,
+        attributes: Vec::new(),
+    });
+    let names = |items: &[BookmarkItem]| items.iter()
+        .map(|item| match item {
+            BookmarkItem::Link(entry) => entry.name.clone(),
+            BookmarkItem::Folder(folder) => folder.name.clone(),
+            BookmarkItem::Separator => "--".to_string(),
+        })
+        .collect::<Vec<_>>();
+    let base = Path::new("/nonexistent");
+
+    let items = vec![link("IMG_10.jpg"), link("IMG_9.jpg"), link("img_2.jpg"), BookmarkItem::Separator, link("b"), link("a")];
+    assert_eq!(names(&sort_bookmarks(items.clone(), BookmarkSort::Natural, base)),
+        ["img_2.jpg", "IMG_9.jpg", "IMG_10.jpg", "--", "a", "b"]);
+    assert_eq!(names(&sort_bookmarks(items, BookmarkSort::Name, base)),
+        ["IMG_10.jpg", "IMG_9.jpg", "img_2.jpg", "--", "a", "b"]);
+
+    // Manual order is kept; new entries go before the first entry sorting after them
+    let items = vec![link("IMG_3.jpg"), link("IMG_1.jpg"), link("IMG_5.jpg"), link("IMG_4.jpg"), link("IMG_2.jpg")];
+    assert_eq!(names(&insert_sorted_bookmarks(items, 3, BookmarkSort::Natural, base)),
+        ["IMG_2.jpg", "IMG_3.jpg", "IMG_1.jpg", "IMG_4.jpg", "IMG_5.jpg"]);
+
+    assert_eq!(parse_exif_datetime("2025:12:21 10:30:00"), Some(1766313000));
+}
+
+#[test]
+fn test_detect_renames() {
+    let tmp = TestDir::new("renames");
+    let dir = tmp.path();
+    fs::create_dir_all(dir.join("left")).unwrap();
+    fs::write(dir.join("speaker-left.jpg"), "a").unwrap();
+    fs::write(dir.join("left/DSCF4463.JPG"), "b").unwrap();
+    fs::write(dir.join("decoy.jpg"), "abc").unwrap();
+    let day = std::time::Duration::from_secs(86400);
+    for name in ["speaker-left.jpg", "decoy.jpg"] {
+        File::options().write(true).open(dir.join(name)).unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + day).unwrap();
+    }
+    File::options().write(true).open(dir.join("left/DSCF4463.JPG")).unwrap()
+        .set_modified(SystemTime::UNIX_EPOCH + day * 2).unwrap();
+    let (_, mtime_a) = get_file_metadata(&dir.join("speaker-left.jpg")).unwrap();
+    let (_, mtime_b) = get_file_metadata(&dir.join("left/DSCF4463.JPG")).unwrap();
+
+    let mut existing = parse_existing_bookmarks(&format!(r#"<DL><p>
+    <DT><A HREF="DSCF4462.JPG" ADD_DATE="1" LAST_MODIFIED="{}" SIZE="1">DSCF4462.JPG</A>
+    <DD>Left speaker
+    <DT><A HREF="DSCF4463.JPG" ADD_DATE="2" LAST_MODIFIED="{}" SIZE="1">Cabinet</A>
+    <DT><A HREF="DSCF4465.JPG" ADD_DATE="3" LAST_MODIFIED="{}">DSCF4465.JPG</A>
+    <DD>Legacy caption
+</DL><p>"#, mtime_a, mtime_b, mtime_a));
+    let scanned = scan_directory_tree(dir, "index.html", None, &BookmarkScanFilter::default()).unwrap();
+
+    let detected = detect_renames(&existing, &scanned, dir).unwrap();
+    let mut moved = HashMap::new();
+    apply_renames(&mut existing, &detected.renames, &mut moved);
+    let items = merge_bookmarks(existing, &scanned, dir, &BookmarkMergeOptions { sync: SyncPolicy::Remove, ..Default::default() }, &mut moved).unwrap();
+
+    // An entry without a recorded size only matches on mtime, so the decoy
+    // is reported rather than inheriting its caption
+    assert_eq!(detected.possible, [("DSCF4465.JPG".to_string(), "decoy.jpg".to_string())]);
+
+    // Renamed in place: the name follows the file, the caption stays. The
+    // decoy has the same mtime but not the same size
+    assert_eq!(items.len(), 3);
+    let BookmarkItem::Link(renamed) = &items[0] else { panic!("expected a link") };
+    assert_eq!(renamed.href, "speaker-left.jpg");
+    assert_eq!(renamed.name, "speaker-left.jpg");
+    assert_eq!(renamed.description.as_deref(), Some("Left speaker"));
+    assert_eq!(renamed.add_date, 1);
+    assert_eq!(renamed.size, Some(1));
+    let BookmarkItem::Link(decoy) = &items[1] else { panic!("expected a link") };
+    assert_eq!(decoy.href, "decoy.jpg");
+    assert_eq!(decoy.description, None);
+
+    // Moved into a folder: a hand-edited name is kept, and the size is
+    // recorded for the next rename
+    let BookmarkItem::Folder(folder) = &items[2] else { panic!("expected a folder") };
+    let BookmarkItem::Link(moved) = &folder.entries[0] else { panic!("expected a link") };
+    assert_eq!(moved.href, "left/DSCF4463.JPG");
+    assert_eq!(moved.name, "Cabinet");
+    assert_eq!(moved.add_date, 2);
+    assert_eq!(moved.size, Some(1));
+}
+
+#[test]
+fn test_merge_bookmarks_sync_policy() {
+    let tmp = TestDir::new("sync");
+    let dir = tmp.path();
+    fs::write(dir.join("kept.jpg"), "a").unwrap();
+
+    let existing = parse_existing_bookmarks(r#"<DL><p>
+    <DT><A HREF="kept.jpg">kept.jpg</A>
+    <DD>Still here
+    <DT><A HREF="gone.jpg" TAGS="speakers">gone.jpg</A>
+    <DT><A HREF="https://example.com/">Example</A>
+    <DT><H3>Deleted album</H3>
+    <DL><p>
+        <DT><A HREF="Deleted%20album/1.jpg">1.jpg</A>
+    </DL><p>
+</DL><p>"#);
+    let scanned = scan_directory_tree(dir, "index.html", None, &BookmarkScanFilter::default()).unwrap();
+
+    let marked = merge_bookmarks(existing.clone(), &scanned, dir, &BookmarkMergeOptions { sync: SyncPolicy::MarkMissing, ..Default::default() }, &mut HashMap::new()).unwrap();
+    let removed = merge_bookmarks(existing, &scanned, dir, &BookmarkMergeOptions { sync: SyncPolicy::Remove, ..Default::default() }, &mut HashMap::new()).unwrap();
+
+    let BookmarkItem::Link(kept) = &marked[0] else { panic!("expected a link") };
+    assert!(kept.tags.is_empty());
+    let BookmarkItem::Link(gone) = &marked[1] else { panic!("expected a link") };
+    assert_eq!(gone.tags, ["speakers", "missing"]);
+    let BookmarkItem::Link(url) = &marked[2] else { panic!("expected a link") };
+    assert!(url.tags.is_empty());
+    let BookmarkItem::Folder(album) = &marked[3] else { panic!("expected a folder") };
+    let BookmarkItem::Link(album_photo) = &album.entries[0] else { panic!("expected a link") };
+    assert_eq!(album_photo.tags, ["missing"]);
+
+    assert_eq!(removed.len(), 2);
+    let BookmarkItem::Link(kept) = &removed[0] else { panic!("expected a link") };
+    assert_eq!(kept.description.as_deref(), Some("Still here"));
+    let BookmarkItem::Link(url) = &removed[1] else { panic!("expected a link") };
+    assert_eq!(url.href, "https://example.com/");
+
+    assert_eq!(local_bookmark_path("kept.jpg?size=large#top", dir), Some(dir.join("kept.jpg")));
+    assert_eq!(local_bookmark_path("a%3Fb.jpg", dir), Some(dir.join("a?b.jpg")));
+}
+
+fn handle_bookmarks_command(
+    folder: &Path,
+    index_name: &str,
+    recursive: bool,
+    max_depth: Option<usize>,
+    filter: &BookmarkScanFilter,
+    options: &BookmarkMergeOptions,
+    write: &BookmarkWriteOptions,
+) -> Result<(), Box<dyn Error>> {
+    // Validate folder exists
+    if !folder.is_dir() {
+        return Err(format!("Path is not a directory: {}", folder.display()).into());
+    }
+
+    let index_path = folder.join(index_name);
+
+    // Read existing bookmarks if file exists
+    let existing_content = if index_path.exists() {
+        fs::read_to_string(&index_path)?
+    } else {
+        String::new()
+    };
+
+    let mut existing_items = if !existing_content.is_empty() {
+        parse_existing_bookmarks(&existing_content)
+    } else {
+        Vec::new()
+    };
+
+    // Scan directory for files and, if recursive, subdirectories
+    let max_depth = if recursive { max_depth } else { Some(0) };
+    let scanned = scan_directory_tree(folder, index_name, max_depth, filter)?;
+
+    let original_items = write.dry_run.then(|| existing_items.clone());
+
+    // Carry entries over to files that were renamed or moved
+    let detected = detect_renames(&existing_items, &scanned, folder)?;
+    let mut moved = HashMap::new();
+    apply_renames(&mut existing_items, &detected.renames, &mut moved);
+    for (old_href, new_href) in &detected.possible {
+        println!("Possible rename, left as is (no recorded size): {} -> {}", old_href, new_href);
+    }
+
+    // Merge existing bookmarks with filesystem
+    let merged_items = merge_bookmarks(existing_items, &scanned, folder, options, &mut moved)?;
+
+    // Generate HTML
+    let folder_name = folder
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("Bookmarks Menu");
+    let html = generate_bookmark_html(folder_name, &merged_items);
+
+    if write.diff {
+        print!("{}", unified_bookmark_diff(&existing_content, &html, &index_path));
+    }
+    let mut renames: Vec<_> = detected.renames.into_iter().collect();
+    renames.sort();
+    if let Some(original_items) = original_items {
+        for (old_href, new_href) in &renames {
+            println!("Would rename: {} -> {}", old_href, new_href);
+        }
+        print_bookmark_changes(&original_items, &merged_items);
+        println!("Dry run, {} not written", index_path.display());
+        return Ok(());
+    }
+
+    if !write_bookmark_index(&index_path, &html, write.backups)? {
+        println!("Bookmark index unchanged: {}", index_path.display());
+        return Ok(());
+    }
+
+    for (old_href, new_href) in &renames {
+        println!("Renamed: {} -> {}", old_href, new_href);
+    }
+    println!("Bookmark index generated: {}", index_path.display());
+    println!("Total entries: {}", merged_items.len());
+
+    Ok(())
+}
+
+// Safe index writes
+//
+// An index holds captions that exist nowhere else, so it is never truncated
+// in place: the new version goes to a temp file that is renamed over the old
+// one, after the old one has been copied to INDEX.bak.1 (older backups shift
+// up to INDEX.bak.N)
+
+const DEFAULT_BOOKMARK_BACKUPS: usize = 3;
+
+fn bookmark_backup_path(index_path: &Path, n: usize) -> PathBuf {
+    let mut name = index_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".bak.{}", n));
+    index_path.with_file_name(name)
+}
+
+fn rotate_bookmark_backups(index_path: &Path, backups: usize) -> Result<(), Box<dyn Error>> {
+    if backups == 0 || !index_path.exists() {
+        return Ok(());
+    }
+    for n in (1..backups).rev() {
+        let from = bookmark_backup_path(index_path, n);
+        if from.exists() {
+            fs::rename(&from, bookmark_backup_path(index_path, n + 1))?;
+        }
+    }
+    fs::copy(index_path, bookmark_backup_path(index_path, 1))?;
+    Ok(())
+}
+
+// Returns false, without touching anything, if the index already holds this content
+fn write_bookmark_index(index_path: &Path, html: &str, backups: usize) -> Result<bool, Box<dyn Error>> {
+    if fs::read_to_string(index_path).is_ok_and(|current| current == html) {
+        return Ok(false);
+    }
+
+    // Hidden, so a scan or watch running meanwhile ignores it
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(index_path.file_name().unwrap_or_default());
+    temp_name.push(format!(".tmp-{}", std::process::id()));
+    let temp_path = index_path.with_file_name(temp_name);
+
+    let result = (|| -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(html.as_bytes())?;
+        file.sync_all()?;
+        rotate_bookmark_backups(index_path, backups)?;
+        fs::rename(&temp_path, index_path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.map(|_| true)
+}
+
+fn handle_bookmarks_restore_command(
+    index: &Path,
+    backup: usize,
+    list: bool,
+    backups: usize,
+) -> Result<(), Box<dyn Error>> {
+    let index_path = resolve_bookmark_index(index);
+
+    if list {
+        let mut n = 1;
+        while let Ok(metadata) = fs::metadata(bookmark_backup_path(&index_path, n)) {
+            let modified: chrono::DateTime<chrono::Local> = metadata.modified()?.into();
+            let content = fs::read_to_string(bookmark_backup_path(&index_path, n))?;
+            let items = parse_existing_bookmarks(&content);
+            let mut links = Vec::new();
+            collect_bookmark_links(&items, &mut links);
+            println!("{}\t{}\t{} links", n, modified.format("%Y-%m-%d %H:%M:%S"), links.len());
+            n += 1;
+        }
+        if n == 1 {
+            println!("No backups of {}", index_path.display());
+        }
+        return Ok(());
+    }
+
+    let backup_path = bookmark_backup_path(&index_path, backup);
+    let content = fs::read_to_string(&backup_path)
+        .map_err(|e| format!("Cannot read {}: {}", backup_path.display(), e))?;
+
+    // The current index becomes backup 1, so a restore can be undone
+    if write_bookmark_index(&index_path, &content, backups)? {
+        println!("Restored {} from {}", index_path.display(), backup_path.display());
+    } else {
+        println!("{} already matches {}", index_path.display(), backup_path.display());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_write_bookmark_index_backups() {
+    let tmp = TestDir::new("backups");
+    let dir = tmp.path();
+    let index_path = dir.join("index.html");
+    let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+
+    for version in ["v1", "v2", "v3", "v4"] {
+        assert!(write_bookmark_index(&index_path, version, 2).unwrap());
+    }
+    // Unchanged content doesn't push another backup
+    assert!(!write_bookmark_index(&index_path, "v4", 2).unwrap());
+
+    assert_eq!(read(index_path.clone()), "v4");
+    assert_eq!(read(bookmark_backup_path(&index_path, 1)), "v3");
+    assert_eq!(read(bookmark_backup_path(&index_path, 2)), "v2");
+    assert!(!bookmark_backup_path(&index_path, 3).exists());
+
+    handle_bookmarks_restore_command(dir, 2, false, 2).unwrap();
+    assert_eq!(read(index_path.clone()), "v2");
+    assert_eq!(read(bookmark_backup_path(&index_path, 1)), "v4");
+    assert_eq!(read(bookmark_backup_path(&index_path, 2)), "v3");
+    assert!(!bookmark_backup_path(&index_path, 3).exists());
+
+    let leftovers = fs::read_dir(dir).unwrap().flatten()
+        .filter(|e| e.file_name().to_string_lossy().contains(".tmp-"))
+        .count();
+    assert_eq!(leftovers, 0);
+}
+
+// Dry runs and diffs
+
+#[derive(Default)]
+struct BookmarkWriteOptions {
+    dry_run: bool,
+    diff: bool,
+    backups: usize,
+}
+
+fn collect_bookmark_links<'a>(items: &'a [BookmarkItem], links: &mut Vec<&'a BookmarkEntry>) {
+    for item in items {
+        match item {
+            BookmarkItem::Link(entry) => links.push(entry),
+            BookmarkItem::Folder(folder) => collect_bookmark_links(&folder.entries, links),
+            BookmarkItem::Separator => {}
+        }
+    }
+}
+
+// Links are matched by href; a renamed file shows up as removed and added
+fn bookmark_changes(old_items: &[BookmarkItem], new_items: &[BookmarkItem]) -> Vec<String> {
+    let (mut old_links, mut new_links) = (Vec::new(), Vec::new());
+    collect_bookmark_links(old_items, &mut old_links);
+    collect_bookmark_links(new_items, &mut new_links);
+    let old_by_href: HashMap<&str, &BookmarkEntry> = old_links.iter().map(|e| (e.href.as_str(), *e)).collect();
+    let new_hrefs: HashSet<&str> = new_links.iter().map(|e| e.href.as_str()).collect();
+
+    let mut changes = Vec::new();
+    for entry in &new_links {
+        let Some(old) = old_by_href.get(entry.href.as_str()) else {
+            changes.push(format!("+ {}", entry.href));
+            continue;
+        };
+        let mut fields = Vec::new();
+        if old.name != entry.name {
+            fields.push("name");
+        }
+        if old.description != entry.description {
+            fields.push("description");
+        }
+        if old.add_date != entry.add_date || old.last_modified != entry.last_modified {
+            fields.push("dates");
+        }
+        if old.size != entry.size {
+            fields.push("size");
+        }
+        if old.tags != entry.tags {
+            fields.push("tags");
+        }
+        if old.shortcut_url != entry.shortcut_url {
+            fields.push("shortcut");
+        }
+        if old.attributes != entry.attributes {
+            fields.push("attributes");
+        }
+        if !fields.is_empty() {
+            changes.push(format!("~ {} ({})", entry.href, fields.join(", ")));
+        }
+    }
+    for entry in &old_links {
+        if !new_hrefs.contains(entry.href.as_str()) {
+            changes.push(format!("- {}", entry.href));
+        }
+    }
+    changes
+}
+
+fn print_bookmark_changes(old_items: &[BookmarkItem], new_items: &[BookmarkItem]) {
+    let changes = bookmark_changes(old_items, new_items);
+    for change in &changes {
+        println!("{}", change);
+    }
+    let count = |prefix: &str| changes.iter().filter(|c| c.starts_with(prefix)).count();
+    println!("{} added, {} removed, {} changed", count("+"), count("-"), count("~"));
+}
+
+fn unified_bookmark_diff(old: &str, new: &str, index_path: &Path) -> String {
+    let name = index_path.display().to_string();
+    similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(&name, &name)
+        .to_string()
+}
+
+#[test]
+fn test_bookmark_changes() {
+    let (_, old) = parse_bookmark_document(r#"<DL><p>
+    <DT><A HREF="DSCF4461.JPG" ADD_DATE="1" LAST_MODIFIED="1">DSCF4461.JPG</A>
+    <DT><A HREF="DSCF4462.JPG" ADD_DATE="1" LAST_MODIFIED="1">DSCF4462.JPG</A>
+    <DT><A HREF="DSCF4463.JPG" ADD_DATE="1" LAST_MODIFIED="1">DSCF4463.JPG</A>
+</DL><p>"#);
+    let (_, new) = parse_bookmark_document(r#"<DL><p>
+    <DT><A HREF="DSCF4462.JPG" ADD_DATE="1" LAST_MODIFIED="1">DSCF4462.JPG</A>
+    <DD>Right speaker
+    <DT><A HREF="DSCF4463.JPG" ADD_DATE="1" LAST_MODIFIED="1">DSCF4463.JPG</A>
+    <DT><H3>raw</H3>
+    <DL><p>
+        <DT><A HREF="raw/DSCF4464.RAF" ADD_DATE="2" LAST_MODIFIED="2">DSCF4464.RAF</A>
+    </DL><p>
+</DL><p>"#);
+    assert_eq!(bookmark_changes(&old, &new), ["~ DSCF4462.JPG (description)", "+ raw/DSCF4464.RAF", "- DSCF4461.JPG"]);
+
+    // Files that are gone pick up the "missing" tag
+    let gone = ScannedDir { path: PathBuf::from("/nonexistent"), files: Vec::new(), dirs: Vec::new() };
+    let options = BookmarkMergeOptions { sync: SyncPolicy::MarkMissing, ..Default::default() };
+    let marked = merge_bookmarks(old.clone(), &gone, &gone.path, &options, &mut HashMap::new()).unwrap();
+    assert_eq!(bookmark_changes(&old, &marked), ["~ DSCF4461.JPG (tags)", "~ DSCF4462.JPG (tags)", "~ DSCF4463.JPG (tags)"]);
+
+    let diff = unified_bookmark_diff("a\nb\n", "a\nc\n", Path::new("index.html"));
+    assert_eq!(diff, "--- index.html\n+++ index.html\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n");
+}
+
+// Bookmark watch mode
+
+// How long the folder has to be quiet before re-syncing, so a camera import
+// triggers one sync rather than one per file
+const BOOKMARK_WATCH_DEBOUNCE: std::time::Duration = std::time::Duration::from_secs(2);
+// A folder that never goes quiet still gets synced this often
+const BOOKMARK_WATCH_MAX_WAIT: std::time::Duration = std::time::Duration::from_secs(30);
+
+// Our own writes to the index (and its backups and temp files) and hidden
+// files other than .sbtignore don't call for a sync
+fn is_bookmark_watch_event(event: &notify::Event, index_name: &str) -> bool {
+    if matches!(event.kind, notify::EventKind::Access(_)) {
+        return false;
+    }
+    event.paths.iter().any(|path| {
+        let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+        name != index_name
+            && !name.starts_with(&format!("{}.", index_name))
+            && (!name.starts_with('.') || name == ".sbtignore")
+    })
+}
+
+fn watch_bookmarks(
+    folder: &Path,
+    index_name: &str,
+    recursive: bool,
+    mut sync: impl FnMut() -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    use notify::Watcher;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    let mode = if recursive { notify::RecursiveMode::Recursive } else { notify::RecursiveMode::NonRecursive };
+    watcher.watch(folder, mode)?;
+    println!("Watching {} for changes (Ctrl-C to stop)", folder.display());
+
+    let relevant = |event: notify::Result<notify::Event>| match event {
+        Ok(event) => is_bookmark_watch_event(&event, index_name),
+        Err(err) => {
+            eprintln!("Watch error: {}", err);
+            false
+        }
+    };
+
+    loop {
+        // Wait for a change, then for the burst of changes to settle
+        if !relevant(rx.recv()?) {
+            continue;
+        }
+        let deadline = std::time::Instant::now() + BOOKMARK_WATCH_MAX_WAIT;
+        loop {
+            let left = deadline.saturating_duration_since(std::time::Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            match rx.recv_timeout(BOOKMARK_WATCH_DEBOUNCE.min(left)) {
+                Ok(event) => {
+                    relevant(event);
+                }
+                Err(_) => break,
+            }
+        }
+
+        // A failed sync (e.g. a file vanishing mid-scan) shouldn't end the watch
+        if let Err(err) = sync() {
+            eprintln!("Error: {}", err);
+        }
+    }
+}
+
+#[test]
+fn test_is_bookmark_watch_event() {
+    use notify::event::{AccessKind, CreateKind, EventKind, ModifyKind, RenameMode};
+
+    let event = |kind: EventKind, name: &str| notify::Event::new(kind).add_path(PathBuf::from("/photos").join(name));
+    let create = EventKind::Create(CreateKind::File);
+
+    assert!(is_bookmark_watch_event(&event(create, "DSCF4462.JPG"), "index.html"));
+    assert!(is_bookmark_watch_event(&event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), "speaker-left.jpg"), "index.html"));
+    assert!(is_bookmark_watch_event(&event(create, ".sbtignore"), "index.html"));
+    assert!(!is_bookmark_watch_event(&event(create, "index.html"), "index.html"));
+    assert!(!is_bookmark_watch_event(&event(create, "index.html.bak.1"), "index.html"));
+    assert!(!is_bookmark_watch_event(&event(create, ".DSCF4462.JPG.tmp"), "index.html"));
+    assert!(!is_bookmark_watch_event(&event(EventKind::Access(AccessKind::Any), "DSCF4462.JPG"), "index.html"));
+}
+
+// Tree to bookmarks functionality
+
+// Absolute paths become file:// URLs; relative ones stay relative so the
+// bookmark file works when saved next to the listed folder
+fn tree_path_to_href(path: &str) -> String {
+    let encoded = path.split('/')
+        .map(|segment| urlencoding::encode(segment).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+    if path.starts_with('/') {
+        format!("file://{}", encoded)
+    } else {
+        encoded
+    }
+}
+
+fn tree_time_to_unix_timestamp(time: Option<&str>) -> u64 {
+    time.and_then(parse_tree_time)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|datetime| datetime.and_utc().timestamp().max(0) as u64)
+        .unwrap_or(0)
+}
+
+fn tree_node_to_bookmark_items(node: &TreeNode, path: &str) -> Vec<BookmarkItem> {
+    let mut items = Vec::new();
+    let Some(contents) = &node.contents else { return items };
+
+    for child in contents {
+        if child.name.is_empty() {
+            continue;
+        }
+        let child_path = format!("{}/{}", path, child.name);
+        let timestamp = tree_time_to_unix_timestamp(child.time.as_deref());
+
+        if child.node_type == "directory" {
+            items.push(BookmarkItem::Folder(BookmarkFolder {
+                name: child.name.clone(),
+                last_modified: timestamp,
+                entries: tree_node_to_bookmark_items(child, &child_path),
+                description: None,
+                attributes: Vec::new(),
+            }));
+        } else {
+            items.push(BookmarkItem::Link(BookmarkEntry {
+                name: child.name.clone(),
+                href: tree_path_to_href(&child_path),
+                add_date: timestamp,
+                last_modified: timestamp,
+                size: None,
+                description: None,
+                tags: Vec::new(),
+                shortcut_url: None,
+                attributes: Vec::new(),
+            }));
+        }
+    }
+
+    items
+}
+
+// Find a directory by path relative to one of the roots, or by its full path
+fn find_tree_subtree<'a>(tree: &'a TreeList, subtree: &str) -> Option<(&'a TreeNode, String)> {
+    for root in &tree.roots {
+        let relative = subtree.strip_prefix(root.name.as_str())
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+            .map(|rest| rest.trim_start_matches('/'))
+            .unwrap_or(subtree);
+
+        let mut node = root;
+        let mut path = root.name.clone();
+        let mut found = true;
+        for segment in relative.split('/').filter(|s| !s.is_empty()) {
+            match node.contents.iter().flatten().find(|child| child.name == segment) {
+                Some(child) => {
+                    path = format!("{}/{}", path, child.name);
+                    node = child;
+                }
+                None => {
+                    found = false;
+                    break;
+                }
+            }
+        }
+        if found {
+            return Some((node, path));
+        }
+    }
+    None
+}
+
+fn handle_tree_bookmarks_command(
+    path: &Path,
+    subtree: Option<&str>,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let tree = load_tree(path)?;
+
+    let (title, items) = match subtree {
+        Some(subtree) => {
+            let (node, node_path) = find_tree_subtree(&tree, subtree)
+                .ok_or_else(|| format!("Subtree not found: {}", subtree))?;
+            (node.name.clone(), tree_node_to_bookmark_items(node, &node_path))
+        }
+        None if tree.roots.len() == 1 => {
+            let root = &tree.roots[0];
+            (root.name.clone(), tree_node_to_bookmark_items(root, &root.name))
+        }
+        // Several roots each become a top-level folder
+        None => {
+            let items = tree.roots.iter()
+                .map(|root| BookmarkItem::Folder(BookmarkFolder {
+                    name: root.name.clone(),
+                    last_modified: tree_time_to_unix_timestamp(root.time.as_deref()),
+                    entries: tree_node_to_bookmark_items(root, &root.name),
+                    description: None,
+                    attributes: Vec::new(),
+                }))
+                .collect();
+            ("Bookmarks Menu".to_string(), items)
+        }
+    };
+
+    let title = title.rsplit('/').find(|s| !s.is_empty()).unwrap_or(&title).to_string();
+    let html = generate_bookmark_html(&title, &items);
+
+    match output {
+        Some(output_path) => {
+            fs::write(output_path, html)?;
+            println!("Bookmark index generated: {}", output_path.display());
+        }
+        None => io::stdout().write_all(html.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_tree_node_to_bookmark_items() {
+    let tree: TreeList = serde_json::from_str(r#"
+    [
+      {"type":"directory","name":"/Volumes/NAS","size":1000,"time":"2023-08-23","contents":[
+        {"type":"directory","name":"Archive","size":500,"time":"2019-02-08","contents":[
+          {"type":"directory","name":"2019","size":300,"time":"2019-02-08","contents":[
+            {"type":"file","name":"XÚÖ West.jpg","size":168,"time":"1970-01-02"}
+          ]}
+        ]}
+      ]}
+    ]"#).unwrap();
+
+    let (node, path) = find_tree_subtree(&tree, "Archive").unwrap();
+    assert_eq!(path, "/Volumes/NAS/Archive");
+
+    let items = tree_node_to_bookmark_items(node, &path);
+    let BookmarkItem::Folder(folder) = &items[0] else { panic!("expected a folder") };
+    assert_eq!(folder.name, "2019");
+    let BookmarkItem::Link(entry) = &folder.entries[0] else { panic!("expected a link") };
+    assert_eq!(entry.href, "file:///Volumes/NAS/Archive/2019/X%C3%9A%C3%96%20West.jpg");
+    assert_eq!(entry.add_date, 86400);
+
+    // Nested folders are written out at every level
+    let html = generate_bookmark_html("Archive", &items);
+    assert!(html.contains("        <DT><A HREF=\"file:///Volumes/NAS/Archive/2019/X%C3%9A%C3%96%20West.jpg\""));
+    assert!(find_tree_subtree(&tree, "Archive/2020").is_none());
+    assert!(find_tree_subtree(&tree, "/Volumes/NASArchive").is_none());
+}
+
+// Bookmark export functionality
+
+// A folder given instead of a file means its index.html
+fn resolve_bookmark_index(path: &Path) -> PathBuf {
+    if path.is_dir() {
+        path.join("index.html")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+fn export_bookmarks_json(title: &str, items: &[BookmarkItem]) -> Result<String, Box<dyn Error>> {
+    let document = serde_json::json!({ "title": title, "items": items });
+    Ok(serde_json::to_string_pretty(&document)? + "\n")
+}
+
+fn markdown_text(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '[' | ']') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+fn single_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn push_markdown_items(markdown: &mut String, items: &[BookmarkItem], depth: usize) {
+    let indent = "  ".repeat(depth);
+    for item in items {
+        match item {
+            BookmarkItem::Link(entry) => {
+                // Angle brackets keep spaces and parentheses in the href intact,
+                // as long as the href itself has none
+                let href = entry.href.replace('<', "%3C").replace('>', "%3E");
+                let href = if href.contains([' ', '(', ')']) {
+                    format!("<{}>", href)
+                } else {
+                    href
+                };
+                markdown.push_str(&format!("{}- [{}]({})", indent, markdown_text(&entry.name), href));
+                if let Some(desc) = &entry.description {
+                    markdown.push_str(&format!(" - {}", markdown_text(&single_line(desc))));
+                }
+                for tag in &entry.tags {
+                    markdown.push_str(&format!(" #{}", tag.replace(' ', "-")));
+                }
+                markdown.push('\n');
+            }
+            BookmarkItem::Folder(folder) => {
+                markdown.push_str(&format!("{}- **{}**", indent, markdown_text(&folder.name)));
+                if let Some(desc) = &folder.description {
+                    markdown.push_str(&format!(" - {}", markdown_text(&single_line(desc))));
+                }
+                markdown.push('\n');
+                push_markdown_items(markdown, &folder.entries, depth + 1);
+            }
+            // A list can't hold a rule; folders and links carry the structure
+            BookmarkItem::Separator => {}
+        }
+    }
+}
+
+fn export_bookmarks_markdown(title: &str, items: &[BookmarkItem]) -> String {
+    let mut markdown = format!("# {}\n\n", markdown_text(title));
+    push_markdown_items(&mut markdown, items, 0);
+    markdown
+}
+
+fn opml_attribute(text: &str) -> String {
+    escape_html(text).replace('\n', "&#10;")
+}
+
+fn push_opml_items(opml: &mut String, items: &[BookmarkItem], depth: usize) {
+    let indent = "  ".repeat(depth);
+    for item in items {
+        match item {
+            BookmarkItem::Link(entry) => {
+                opml.push_str(&format!(
+                    "{}<outline text=\"{}\" type=\"link\" url=\"{}\" created=\"{}\"",
+                    indent, opml_attribute(&entry.name), opml_attribute(&entry.href),
+                    format_rfc822_timestamp(entry.add_date)
+                ));
+                if let Some(desc) = &entry.description {
+                    opml.push_str(&format!(" _note=\"{}\"", opml_attribute(desc)));
+                }
+                opml.push_str("/>\n");
+            }
+            BookmarkItem::Folder(folder) => {
+                opml.push_str(&format!("{}<outline text=\"{}\"", indent, opml_attribute(&folder.name)));
+                if let Some(desc) = &folder.description {
+                    opml.push_str(&format!(" _note=\"{}\"", opml_attribute(desc)));
+                }
+                opml.push_str(">\n");
+                push_opml_items(opml, &folder.entries, depth + 1);
+                opml.push_str(&format!("{}</outline>\n", indent));
+            }
+            BookmarkItem::Separator => {}
+        }
+    }
+}
+
+fn format_rfc822_timestamp(timestamp: u64) -> String {
+    chrono::DateTime::from_timestamp(timestamp as i64, 0)
+        .unwrap_or_default()
+        .to_rfc2822()
+}
+
+fn export_bookmarks_opml(title: &str, items: &[BookmarkItem]) -> String {
+    let mut opml = String::new();
+    opml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    opml.push_str("<opml version=\"2.0\">\n");
+    opml.push_str(&format!("<head>\n  <title>{}</title>\n</head>\n", escape_html(title)));
+    opml.push_str("<body>\n");
+    push_opml_items(&mut opml, items, 1);
+    opml.push_str("</body>\n</opml>\n");
+    opml
+}
+
+#[derive(Serialize)]
+struct BookmarkCsvRow<'a> {
+    folder: String,
+    name: &'a str,
+    href: &'a str,
+    add_date: u64,
+    last_modified: u64,
+    description: &'a str,
+    tags: String,
+}
+
+fn push_csv_rows(
+    writer: &mut csv::Writer<Vec<u8>>,
+    items: &[BookmarkItem],
+    folder: &str,
+) -> Result<(), Box<dyn Error>> {
+    for item in items {
+        match item {
+            BookmarkItem::Link(entry) => writer.serialize(BookmarkCsvRow {
+                folder: folder.to_string(),
+                name: &entry.name,
+                href: &entry.href,
+                add_date: entry.add_date,
+                last_modified: entry.last_modified,
+                description: entry.description.as_deref().unwrap_or(""),
+                tags: entry.tags.join(","),
+            })?,
+            BookmarkItem::Folder(sub) => {
+                let path = if folder.is_empty() { sub.name.clone() } else { format!("{}/{}", folder, sub.name) };
+                push_csv_rows(writer, &sub.entries, &path)?;
+            }
+            BookmarkItem::Separator => {}
+        }
+    }
+    Ok(())
+}
+
+fn export_bookmarks_csv(items: &[BookmarkItem]) -> Result<String, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    push_csv_rows(&mut writer, items, "")?;
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
 
-        // Skip hidden files (starting with .)
-        if file_name_str.starts_with('.') {
-            continue;
-        }
+fn handle_bookmarks_export_command(
+    index: &Path,
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let index_path = resolve_bookmark_index(index);
+    let content = fs::read_to_string(&index_path)
+        .map_err(|e| format!("Cannot read {}: {}", index_path.display(), e))?;
+    let (title, items) = parse_bookmark_document(&content);
+
+    let exported = match format {
+        ExportFormat::Json => export_bookmarks_json(&title, &items)?,
+        ExportFormat::Markdown => export_bookmarks_markdown(&title, &items),
+        ExportFormat::Opml => export_bookmarks_opml(&title, &items),
+        ExportFormat::Csv => export_bookmarks_csv(&items)?,
+    };
 
-        if path.is_dir() {
-            if recursive {
-                dirs.push(path);
-            }
-        } else if path.is_file() {
-            files.push(path);
+    match output {
+        Some(output_path) => {
+            fs::write(output_path, exported)?;
+            println!("Bookmarks exported: {}", output_path.display());
         }
+        None => io::stdout().write_all(exported.as_bytes())?,
     }
 
-    // Sort alphabetically
-    files.sort();
-    dirs.sort();
+    Ok(())
+}
 
-    Ok((files, dirs))
+#[test]
+fn test_export_bookmarks() {
+    let (title, items) = parse_bookmark_document(r#"<H1>XÚÖ</H1>
+<DL><p>
+    <DT><A HREF="DSCF4462.JPG" ADD_DATE="1766354780" LAST_MODIFIED="1766354780" TAGS="speakers">DSCF4462.JPG</A>
+    <DD>Left speaker &amp; *stand*
+    <HR>
+    <DT><H3 LAST_MODIFIED="1">Web [links]</H3>
+    <DL><p>
+        <DT><A HREF="https://example.com/a (b)?q=&lt;x&gt;" ADD_DATE="0" LAST_MODIFIED="0">Example_1</A>
+    </DL><p>
+</DL><p>"#);
+
+    let json: serde_json::Value = serde_json::from_str(&export_bookmarks_json(&title, &items).unwrap()).unwrap();
+    assert_eq!(json["title"], "XÚÖ");
+    assert_eq!(json["items"][0]["type"], "link");
+    assert_eq!(json["items"][0]["description"], "Left speaker & *stand*");
+    assert_eq!(json["items"][0]["tags"][0], "speakers");
+    assert_eq!(json["items"][1]["type"], "separator");
+    assert_eq!(json["items"][2]["entries"][0]["href"], "https://example.com/a (b)?q=<x>");
+
+    assert_eq!(export_bookmarks_markdown(&title, &items), "# XÚÖ\n\n\
+        - [DSCF4462.JPG](DSCF4462.JPG) - Left speaker & \\*stand\\* #speakers\n\
+        - **Web \\[links\\]**\n\
+        \x20 - [Example\\_1](<https://example.com/a (b)?q=%3Cx%3E>)\n");
+
+    let opml = export_bookmarks_opml(&title, &items);
+    assert!(opml.contains(r#"<outline text="DSCF4462.JPG" type="link" url="DSCF4462.JPG" created="Sun, 21 Dec 2025 22:06:20 +0000" _note="Left speaker &amp; *stand*"/>"#));
+    assert!(opml.contains("  <outline text=\"Web [links]\">\n    <outline text=\"Example_1\""));
+
+    assert_eq!(export_bookmarks_csv(&items).unwrap(), "folder,name,href,add_date,last_modified,description,tags\n\
+        ,DSCF4462.JPG,DSCF4462.JPG,1766354780,1766354780,Left speaker & *stand*,speakers\n\
+        Web [links],Example_1,https://example.com/a (b)?q=<x>,0,0,,\n");
 }
 
-fn extract_title_from_bookmarks(content: &str) -> String {
-    let title_re = Regex::new(r"<H1>(.*?)</H1>").unwrap();
-    if let Some(cap) = title_re.captures(content) {
-        cap.get(1).map(|m| m.as_str().to_string()).unwrap_or_else(|| "Untitled".to_string())
-    } else {
-        "Untitled".to_string()
+// Bookmark import functionality
+
+// Firefox keeps times in microseconds
+fn firefox_time_to_unix_timestamp(value: &serde_json::Value) -> u64 {
+    value.as_u64().map(|us| us / 1_000_000).unwrap_or(0)
+}
+
+fn firefox_node_to_bookmark_item(node: &serde_json::Value) -> Option<BookmarkItem> {
+    let text = |key: &str| node.get(key).and_then(|v| v.as_str()).filter(|v| !v.is_empty());
+    let description = node.get("annos").and_then(|a| a.as_array()).and_then(|annos| {
+        annos.iter()
+            .find(|anno| anno["name"] == "bookmarkProperties/description")
+            .and_then(|anno| anno["value"].as_str())
+            .map(str::to_string)
+    });
+
+    match text("type")? {
+        "text/x-moz-place" => {
+            let href = text("uri")?.to_string();
+            Some(BookmarkItem::Link(BookmarkEntry {
+                name: text("title").unwrap_or(&href).to_string(),
+                href,
+                add_date: firefox_time_to_unix_timestamp(&node["dateAdded"]),
+                last_modified: firefox_time_to_unix_timestamp(&node["lastModified"]),
+                size: None,
+                description,
+                tags: text("tags").map(parse_tags).unwrap_or_default(),
+                shortcut_url: text("keyword").map(str::to_string),
+                attributes: Vec::new(),
+            }))
+        }
+        "text/x-moz-place-container" => Some(BookmarkItem::Folder(BookmarkFolder {
+            // Built-in folders get the names Firefox uses in its HTML export
+            name: match text("root") {
+                Some("bookmarksMenuFolder") => "Bookmarks Menu",
+                Some("toolbarFolder") => "Bookmarks Toolbar",
+                Some("unfiledBookmarksFolder") => "Other Bookmarks",
+                Some("mobileFolder") => "Mobile Bookmarks",
+                _ => text("title").unwrap_or("Untitled"),
+            }.to_string(),
+            last_modified: firefox_time_to_unix_timestamp(&node["lastModified"]),
+            entries: firefox_children_to_bookmark_items(node),
+            description,
+            attributes: Vec::new(),
+        })),
+        "text/x-moz-place-separator" => Some(BookmarkItem::Separator),
+        _ => None,
     }
 }
 
-fn parse_existing_bookmarks(content: &str) -> Vec<BookmarkItem> {
-    let mut items = Vec::new();
+fn firefox_children_to_bookmark_items(node: &serde_json::Value) -> Vec<BookmarkItem> {
+    node.get("children").and_then(|c| c.as_array())
+        .map(|children| children.iter().filter_map(firefox_node_to_bookmark_item).collect())
+        .unwrap_or_default()
+}
 
-    // Find the main DL block
-    let main_dl_re = Regex::new(r"(?s)<H1>.*?</H1>\s*<DL><p>(.*)</DL><p>\s*$").unwrap();
-    let main_content = match main_dl_re.captures(content) {
-        Some(cap) => cap.get(1).map(|m| m.as_str()).unwrap_or(""),
-        None => return items,
+// Netscape HTML as exported by every browser, or a Firefox JSON backup
+fn parse_browser_bookmarks(content: &str) -> Result<Vec<BookmarkItem>, Box<dyn Error>> {
+    if !content.trim_start().starts_with('{') {
+        return Ok(parse_existing_bookmarks(content));
+    }
+    // The backup's root holds the menu, toolbar and other bookmarks folders
+    let root: serde_json::Value = serde_json::from_str(content)?;
+    Ok(firefox_children_to_bookmark_items(&root))
+}
+
+// Lowercase scheme and host, drop default ports, empty fragments and a bare
+// trailing slash, so the same page bookmarked twice is recognized
+fn normalize_bookmark_url(href: &str) -> String {
+    let href = href.trim();
+    let href = href.strip_suffix('#').unwrap_or(href);
+    let Some((scheme, rest)) = href.split_once("://") else {
+        return href.to_string();
+    };
+    let scheme = scheme.to_lowercase();
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+
+    let mut authority = authority.to_lowercase();
+    let default_port = match scheme.as_str() {
+        "http" => Some(":80"),
+        "https" => Some(":443"),
+        _ => None,
     };
+    if let Some(port) = default_port {
+        if let Some(host) = authority.strip_suffix(port) {
+            authority = host.to_string();
+        }
+    }
 
-    // Parse top-level entries
-    let mut pos = 0;
-    let lines: Vec<&str> = main_content.lines().collect();
-    let mut i = 0;
+    let path = if path == "/" { "" } else { path };
+    format!("{}://{}{}", scheme, authority, path)
+}
 
-    while i < lines.len() {
-        let line = lines[i].trim();
-
-        // Check for link entry
-        if line.starts_with("<DT><A ") {
-            let link_re = Regex::new(
-                r#"<DT><A\s+HREF="([^"]+)"(?:\s+ADD_DATE="(\d+)")?(?:\s+LAST_MODIFIED="(\d+)")?>([^<]+)</A>"#
-            ).unwrap();
-
-            if let Some(cap) = link_re.captures(line) {
-                let mut entry = BookmarkEntry {
-                    href: cap.get(1).map(|m| m.as_str().to_string()).unwrap_or_default(),
-                    add_date: cap.get(2).and_then(|m| m.as_str().parse().ok()).unwrap_or(0),
-                    last_modified: cap.get(3).and_then(|m| m.as_str().parse().ok()).unwrap_or(0),
-                    name: cap.get(4).map(|m| m.as_str().to_string()).unwrap_or_default(),
-                    description: None,
-                };
+fn collect_normalized_urls(items: &[BookmarkItem], urls: &mut HashSet<String>) {
+    for item in items {
+        match item {
+            BookmarkItem::Link(entry) => {
+                urls.insert(normalize_bookmark_url(&entry.href));
+            }
+            BookmarkItem::Folder(folder) => collect_normalized_urls(&folder.entries, urls),
+            BookmarkItem::Separator => {}
+        }
+    }
+}
 
-                // Check next line for description
-                if i + 1 < lines.len() && lines[i + 1].trim().starts_with("<DD>") {
-                    let desc = lines[i + 1].trim().strip_prefix("<DD>").unwrap_or("").trim();
-                    entry.description = Some(desc.to_string());
-                    i += 1;
+// Like merge_bookmarks, but the new entries come from another bookmark
+// file: new links go after the existing items and new folders after
+// them. Returns the number of links added
+fn merge_imported_bookmarks(
+    items: &mut Vec<BookmarkItem>,
+    imported: Vec<BookmarkItem>,
+    seen_urls: &mut HashSet<String>,
+) -> usize {
+    let mut added = 0;
+    let mut folders = Vec::new();
+    for item in imported {
+        match item {
+            BookmarkItem::Link(entry) => {
+                if seen_urls.insert(normalize_bookmark_url(&entry.href)) {
+                    items.push(BookmarkItem::Link(entry));
+                    added += 1;
                 }
-
-                items.push(BookmarkItem::Link(entry));
             }
+            BookmarkItem::Folder(folder) => folders.push(folder),
+            // Separators only mean something among the file's own entries
+            BookmarkItem::Separator => {}
         }
-        // Check for folder entry
-        else if line.starts_with("<DT><H3") {
-            let folder_re = Regex::new(
-                r#"<DT><H3(?:\s+ADD_DATE="(\d+)")?(?:\s+LAST_MODIFIED="(\d+)")?>([^<]+)</H3>"#
-            ).unwrap();
-
-            if let Some(cap) = folder_re.captures(line) {
-                let folder_name = cap.get(3).map(|m| m.as_str().to_string()).unwrap_or_default();
-                let last_modified = cap.get(2).and_then(|m| m.as_str().parse().ok()).unwrap_or(0);
+    }
 
-                // Find the nested DL block for this folder
-                let mut folder_entries = Vec::new();
-                i += 1;
+    for mut folder in folders {
+        let entries = std::mem::take(&mut folder.entries);
+        if let Some(existing) = find_bookmark_folder_mut(items, &folder.name) {
+            added += merge_imported_bookmarks(&mut existing.entries, entries, seen_urls);
+        } else {
+            let folder_added = merge_imported_bookmarks(&mut folder.entries, entries, seen_urls);
+            // Skip folders that held nothing new
+            if folder_added > 0 {
+                items.push(BookmarkItem::Folder(folder));
+                added += folder_added;
+            }
+        }
+    }
+    added
+}
 
-                if i < lines.len() && lines[i].trim() == "<DL><p>" {
-                    i += 1;
-
-                    // Parse folder contents until we hit </DL>
-                    while i < lines.len() && !lines[i].trim().starts_with("</DL>") {
-                        let folder_line = lines[i].trim();
-
-                        if folder_line.starts_with("<DT><A ") {
-                            let link_re = Regex::new(
-                                r#"<DT><A\s+HREF="([^"]+)"(?:\s+ADD_DATE="(\d+)")?(?:\s+LAST_MODIFIED="(\d+)")?>([^<]+)</A>"#
-                            ).unwrap();
-
-                            if let Some(cap) = link_re.captures(folder_line) {
-                                let mut entry = BookmarkEntry {
-                                    href: cap.get(1).map(|m| m.as_str().to_string()).unwrap_or_default(),
-                                    add_date: cap.get(2).and_then(|m| m.as_str().parse().ok()).unwrap_or(0),
-                                    last_modified: cap.get(3).and_then(|m| m.as_str().parse().ok()).unwrap_or(0),
-                                    name: cap.get(4).map(|m| m.as_str().to_string()).unwrap_or_default(),
-                                    description: None,
-                                };
-
-                                // Check next line for description
-                                if i + 1 < lines.len() && lines[i + 1].trim().starts_with("<DD>") {
-                                    let desc = lines[i + 1].trim().strip_prefix("<DD>").unwrap_or("").trim();
-                                    entry.description = Some(desc.to_string());
-                                    i += 1;
-                                }
-
-                                folder_entries.push(BookmarkItem::Link(entry));
-                            }
-                        }
+fn handle_bookmarks_import_command(file: &Path, into: &Path, backups: usize) -> Result<(), Box<dyn Error>> {
+    let content = fs::read_to_string(file)
+        .map_err(|e| format!("Cannot read {}: {}", file.display(), e))?;
+    let imported = parse_browser_bookmarks(&content)?;
 
-                        i += 1;
-                    }
-                }
+    let index_path = resolve_bookmark_index(into);
+    let (title, mut items) = if index_path.exists() {
+        parse_bookmark_document(&fs::read_to_string(&index_path)?)
+    } else {
+        let title = index_path.parent()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or("Bookmarks Menu")
+            .to_string();
+        (title, Vec::new())
+    };
 
-                items.push(BookmarkItem::Folder(BookmarkFolder {
-                    name: folder_name,
-                    last_modified,
-                    entries: folder_entries,
-                }));
-            }
-        }
+    let mut seen_urls = HashSet::new();
+    collect_normalized_urls(&items, &mut seen_urls);
+    let added = merge_imported_bookmarks(&mut items, imported, &mut seen_urls);
 
-        i += 1;
+    if !write_bookmark_index(&index_path, &generate_bookmark_html(&title, &items), backups)? {
+        println!("Bookmark index unchanged: {}", index_path.display());
+        return Ok(());
     }
+    println!("Bookmark index updated: {}", index_path.display());
+    println!("Imported entries: {}", added);
 
-    items
+    Ok(())
 }
 
-fn generate_bookmark_html(
-    folder_name: &str,
-    items: &[BookmarkItem],
-) -> String {
-    let mut html = String::new();
+#[test]
+fn test_import_browser_bookmarks() {
+    let firefox = r#"{"guid":"root________","title":"","type":"text/x-moz-place-container","root":"placesRoot","children":[
+        {"title":"menu","type":"text/x-moz-place-container","root":"bookmarksMenuFolder","lastModified":1700000000000000,"children":[
+            {"title":"Rust","type":"text/x-moz-place","uri":"HTTPS://www.Rust-lang.org:443/","dateAdded":1600000000000000,"tags":"lang,rust","keyword":"rs"},
+            {"type":"text/x-moz-place-separator"},
+            {"title":"Docs","type":"text/x-moz-place-container","children":[
+                {"title":"Std","type":"text/x-moz-place","uri":"https://doc.rust-lang.org/std/","annos":[{"name":"bookmarkProperties/description","value":"The standard library"}]}
+            ]}
+        ]},
+        {"title":"toolbar","type":"text/x-moz-place-container","root":"toolbarFolder","children":[
+            {"title":"Example","type":"text/x-moz-place","uri":"https://example.com"}
+        ]}
+    ]}"#;
+    let imported = parse_browser_bookmarks(firefox).unwrap();
+    assert_eq!(imported.len(), 2);
+    let BookmarkItem::Folder(menu) = &imported[0] else { panic!("expected a folder") };
+    let BookmarkItem::Link(rust) = &menu.entries[0] else { panic!("expected a link") };
+    assert_eq!(rust.add_date, 1600000000);
+    assert_eq!(rust.tags, ["lang", "rust"]);
+    assert_eq!(rust.shortcut_url.as_deref(), Some("rs"));
+
+    assert_eq!(normalize_bookmark_url("HTTPS://www.Rust-lang.org:443/"), "https://www.rust-lang.org");
+    assert_eq!(normalize_bookmark_url("https://example.com/A?b=C#"), "https://example.com/A?b=C");
+    assert_eq!(normalize_bookmark_url("DSCF4462.JPG"), "DSCF4462.JPG");
+
+    // The index already has the Rust link (spelled differently) and a menu folder
+    let (_, mut items) = parse_bookmark_document(r#"<DL><p>
+    <DT><A HREF="DSCF4462.JPG">DSCF4462.JPG</A>
+    <DT><H3>Bookmarks Menu</H3>
+    <DL><p>
+        <DT><A HREF="https://www.rust-lang.org">Rust</A>
+    </DL><p>
+</DL><p>"#);
+    let mut seen_urls = HashSet::new();
+    collect_normalized_urls(&items, &mut seen_urls);
+    let netscape = r#"<DL><p>
+    <DT><H3>Later</H3>
+    <DL><p><DT><A HREF="https://later.example.com">Later</A></DL><p>
+    <DT><A HREF="https://example.com/">Example again</A>
+    <DT><A HREF="https://new.example.com">New</A>
+</DL><p>"#;
+
+    assert_eq!(merge_imported_bookmarks(&mut items, imported, &mut seen_urls), 2);
+    assert_eq!(merge_imported_bookmarks(&mut items, parse_browser_bookmarks(netscape).unwrap(), &mut seen_urls), 2);
+
+    // New links come before new folders, as in a rescan
+    assert_eq!(items.len(), 5);
+    assert!(matches!(&items[3], BookmarkItem::Link(new) if new.name == "New"));
+    assert!(matches!(&items[4], BookmarkItem::Folder(later) if later.name == "Later"));
+    let BookmarkItem::Folder(menu) = &items[1] else { panic!("expected a folder") };
+    assert_eq!(menu.entries.len(), 2);
+    let BookmarkItem::Folder(docs) = &menu.entries[1] else { panic!("expected a folder") };
+    let BookmarkItem::Link(std_docs) = &docs.entries[0] else { panic!("expected a link") };
+    assert_eq!(std_docs.description.as_deref(), Some("The standard library"));
+    let BookmarkItem::Folder(toolbar) = &items[2] else { panic!("expected a folder") };
+    assert_eq!(toolbar.name, "Bookmarks Toolbar");
+}
 
-    html.push_str("<!DOCTYPE NETSCAPE-Bookmark-file-1>\n");
-    html.push_str("<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n");
-    html.push_str("<!-- This is an automatically generated file. It will be read and modified by automated tools. Edit only if you understand the risks -->\n");
-    html.push_str("<TITLE>Bookmarks</TITLE>\n");
-    html.push_str(&format!("<H1>{}</H1>\n", folder_name));
-    html.push_str("<DL><p>\n");
+// Bookmark caption functionality
 
+fn collect_bookmark_links_mut<'a>(items: &'a mut [BookmarkItem], links: &mut Vec<&'a mut BookmarkEntry>) {
     for item in items {
         match item {
-            BookmarkItem::Link(entry) => {
-                html.push_str(&format!(
-                    "    <DT><A HREF=\"{}\" ADD_DATE=\"{}\" LAST_MODIFIED=\"{}\">{}</A>\n",
-                    entry.href, entry.add_date, entry.last_modified, entry.name
-                ));
-                if let Some(desc) = &entry.description {
-                    html.push_str(&format!("    <DD>{}\n", desc));
-                }
-            }
-            BookmarkItem::Folder(folder) => {
-                html.push_str(&format!(
-                    "    <DT><H3 LAST_MODIFIED=\"{}\">{}</H3>\n",
-                    folder.last_modified, folder.name
-                ));
-                html.push_str("    <DL><p>\n");
-                for sub_item in &folder.entries {
-                    if let BookmarkItem::Link(entry) = sub_item {
-                        html.push_str(&format!(
-                            "        <DT><A HREF=\"{}\" ADD_DATE=\"{}\" LAST_MODIFIED=\"{}\">{}</A>\n",
-                            entry.href, entry.add_date, entry.last_modified, entry.name
-                        ));
-                        if let Some(desc) = &entry.description {
-                            html.push_str(&format!("        <DD>{}\n", desc));
-                        }
-                    }
-                }
-                html.push_str("    </DL><p>\n");
-            }
+            BookmarkItem::Link(entry) => links.push(entry),
+            BookmarkItem::Folder(folder) => collect_bookmark_links_mut(&mut folder.entries, links),
+            BookmarkItem::Separator => {}
         }
     }
-
-    html.push_str("</DL><p>\n");
-    html
 }
 
-fn merge_bookmarks(
-    existing: Vec<BookmarkItem>,
-    files: &[PathBuf],
-    dirs: &[(PathBuf, Vec<PathBuf>)],
-    base_path: &Path,
-) -> Result<Vec<BookmarkItem>, Box<dyn Error>> {
-    let mut items = Vec::new();
-    let mut existing_file_hrefs = HashSet::new();
-    let mut existing_folder_names: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
-
-    // Collect existing entries and preserve them
-    for (idx, item) in existing.into_iter().enumerate() {
-        match &item {
-            BookmarkItem::Link(entry) => {
-                existing_file_hrefs.insert(entry.href.clone());
-            }
-            BookmarkItem::Folder(folder) => {
-                existing_folder_names.insert(folder.name.clone(), items.len());
+// An entry is found by its href or decoded href, or failing that by its
+// file name or displayed name, which several entries may share
+fn find_bookmark_entry<'a>(items: &'a mut [BookmarkItem], file: &str) -> Result<&'a mut BookmarkEntry, Box<dyn Error>> {
+    let mut links = Vec::new();
+    collect_bookmark_links_mut(items, &mut links);
+    let decoded = |href: &str| urlencoding::decode(href).map(|h| h.into_owned()).unwrap_or_default();
+    let mut matches: Vec<_> = links.into_iter()
+        .filter_map(|entry| {
+            if entry.href == file || decoded(&entry.href) == file {
+                Some((0, entry))
+            } else if href_file_name(&entry.href) == file || entry.name == file {
+                Some((1, entry))
+            } else {
+                None
             }
-        }
-        items.push(item);
+        })
+        .collect();
+    if let Some(best) = matches.iter().map(|(rank, _)| *rank).min() {
+        matches.retain(|(rank, _)| *rank == best);
     }
 
-    // Add new file entries
-    for file_path in files {
-        let file_name = file_path.file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("");
-        let encoded_name = urlencoding::encode(file_name);
-
-        if !existing_file_hrefs.contains(encoded_name.as_ref()) {
-            let (add_date, last_modified) = get_file_metadata(file_path)?;
-            items.push(BookmarkItem::Link(BookmarkEntry {
-                name: file_name.to_string(),
-                href: encoded_name.to_string(),
-                add_date,
-                last_modified,
-                description: None,
-            }));
+    match matches.len() {
+        0 => Err(format!("No entry for {}", file).into()),
+        1 => Ok(matches.remove(0).1),
+        _ => {
+            let hrefs: Vec<_> = matches.iter().map(|(_, entry)| entry.href.as_str()).collect();
+            Err(format!("{} matches several entries, use the full href: {}", file, hrefs.join(", ")).into())
         }
     }
+}
 
-    // Add or update directory entries
-    for (dir_path, dir_files) in dirs {
-        let dir_name = dir_path.file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("");
+fn set_caption(entry: &mut BookmarkEntry, text: &str) {
+    let text = text.trim();
+    entry.description = (!text.is_empty()).then(|| text.to_string());
+}
 
-        if let Some(&folder_idx) = existing_folder_names.get(dir_name) {
-            // Folder exists, update its entries
-            if let Some(BookmarkItem::Folder(ref mut folder)) = items.get_mut(folder_idx) {
-                // Get existing hrefs in this folder
-                let mut existing_folder_hrefs = HashSet::new();
-                for entry in &folder.entries {
-                    if let BookmarkItem::Link(link) = entry {
-                        existing_folder_hrefs.insert(link.href.clone());
-                    }
-                }
+fn describe_bookmark_file(entry: &BookmarkEntry, base_path: &Path) -> String {
+    let Some(path) = local_bookmark_path(&entry.href, base_path) else {
+        return entry.href.clone();
+    };
+    let Ok(metadata) = fs::metadata(&path) else {
+        return "file missing".to_string();
+    };
+    let format_time = |timestamp: u64| chrono::DateTime::from_timestamp(timestamp as i64, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default();
 
-                // Add new files to this folder
-                for file_path in dir_files {
-                    let file_name = file_path.file_name()
-                        .and_then(|n| n.to_str())
-                        .unwrap_or("");
-                    let relative_path = file_path.strip_prefix(base_path)
-                        .unwrap_or(file_path);
-                    let encoded_path = encode_path_preserving_slashes(relative_path);
-
-                    if !existing_folder_hrefs.contains(&encoded_path) {
-                        let (add_date, file_last_modified) = get_file_metadata(file_path)?;
-                        folder.entries.push(BookmarkItem::Link(BookmarkEntry {
-                            name: file_name.to_string(),
-                            href: encoded_path,
-                            add_date,
-                            last_modified: file_last_modified,
-                            description: None,
-                        }));
-                    }
+    let mut info = format!("{}, modified {}", format_size(metadata.len()), format_time(entry.last_modified));
+    if let Some(taken) = is_image_file(&path).then(|| read_exif_capture_time(&path)).flatten() {
+        info.push_str(&format!(", taken {}", format_time(taken)));
+    }
+    info
+}
+
+// Walk through the entries, prompting for each caption. Enter keeps it, ":-"
+// removes it, ":q" or end of input stops. Returns the number of captions changed
+fn caption_interactively(
+    items: &mut [BookmarkItem],
+    base_path: &Path,
+    only_missing: bool,
+    input: &mut impl io::BufRead,
+    output: &mut impl Write,
+) -> Result<usize, Box<dyn Error>> {
+    let mut links = Vec::new();
+    collect_bookmark_links_mut(items, &mut links);
+    links.retain(|entry| !only_missing || entry.description.is_none());
+
+    let total = links.len();
+    let mut changed = 0;
+    for (i, entry) in links.into_iter().enumerate() {
+        writeln!(output, "\n[{}/{}] {}", i + 1, total, entry.name)?;
+        writeln!(output, "  file: {}", describe_bookmark_file(entry, base_path))?;
+        writeln!(output, "  caption: {}", entry.description.as_deref().unwrap_or("(none)"))?;
+        write!(output, "> ")?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        match line.trim() {
+            "" => {}
+            ":q" => break,
+            ":-" => {
+                if entry.description.take().is_some() {
+                    changed += 1;
                 }
             }
-        } else {
-            // New folder, create it
-            let (_, last_modified) = get_file_metadata(dir_path)?;
-            let mut folder_entries = Vec::new();
-
-            for file_path in dir_files {
-                let file_name = file_path.file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("");
-                let relative_path = file_path.strip_prefix(base_path)
-                    .unwrap_or(file_path);
-                let encoded_path = encode_path_preserving_slashes(relative_path);
-
-                let (add_date, file_last_modified) = get_file_metadata(file_path)?;
-                folder_entries.push(BookmarkItem::Link(BookmarkEntry {
-                    name: file_name.to_string(),
-                    href: encoded_path,
-                    add_date,
-                    last_modified: file_last_modified,
-                    description: None,
-                }));
+            text => {
+                if entry.description.as_deref() != Some(text) {
+                    set_caption(entry, text);
+                    changed += 1;
+                }
             }
-
-            items.push(BookmarkItem::Folder(BookmarkFolder {
-                name: dir_name.to_string(),
-                last_modified,
-                entries: folder_entries,
-            }));
         }
     }
-
-    Ok(items)
+    Ok(changed)
 }
 
-fn handle_bookmarks_command(
-    folder: &PathBuf,
-    index_name: &str,
-    recursive: bool,
+fn handle_bookmarks_caption_command(
+    index: &Path,
+    file: Option<&str>,
+    text: Option<&str>,
+    only_missing: bool,
+    backups: usize,
 ) -> Result<(), Box<dyn Error>> {
-    // Validate folder exists
-    if !folder.is_dir() {
-        return Err(format!("Path is not a directory: {}", folder.display()).into());
+    let index_path = resolve_bookmark_index(index);
+    let content = fs::read_to_string(&index_path)
+        .map_err(|e| format!("Cannot read {}: {}", index_path.display(), e))?;
+    let (title, mut items) = parse_bookmark_document(&content);
+    let base_path = index_path.parent().unwrap_or(Path::new("."));
+
+    match (file, text) {
+        (Some(file), None) => {
+            let entry = find_bookmark_entry(&mut items, file)?;
+            println!("{}", entry.description.as_deref().unwrap_or(""));
+            return Ok(());
+        }
+        (Some(file), Some(text)) => {
+            let entry = find_bookmark_entry(&mut items, file)?;
+            set_caption(entry, text);
+        }
+        _ => {
+            println!("Enter keeps a caption, :- removes it, :q saves and quits");
+            let changed = caption_interactively(&mut items, base_path, only_missing, &mut io::stdin().lock(), &mut io::stdout())?;
+            println!("\n{} captions changed", changed);
+            if changed == 0 {
+                return Ok(());
+            }
+        }
     }
 
-    let index_path = folder.join(index_name);
+    write_bookmark_index(&index_path, &generate_bookmark_html(&title, &items), backups)?;
+    println!("Bookmark index updated: {}", index_path.display());
 
-    // Read existing bookmarks if file exists
-    let existing_content = if index_path.exists() {
-        fs::read_to_string(&index_path)?
-    } else {
-        String::new()
-    };
+    Ok(())
+}
 
-    let existing_items = if !existing_content.is_empty() {
-        parse_existing_bookmarks(&existing_content)
-    } else {
-        Vec::new()
-    };
+#[test]
+fn test_caption_bookmarks() {
+    let (_, mut items) = parse_bookmark_document(r#"<DL><p>
+    <DT><A HREF="DSCF4462.JPG">DSCF4462.JPG</A>
+    <DD>Right speaker
+    <DT><A HREF="DSCF4463.JPG">DSCF4463.JPG</A>
+    <DT><H3>XÚÖ</H3>
+    <DL><p>
+        <DT><A HREF="X%C3%9A%C3%96/DSCF4464.JPG">DSCF4464.JPG</A>
+        <DD>Old caption
+        <DT><A HREF="X%C3%9A%C3%96/DSCF4465.JPG">DSCF4465.JPG</A>
+    </DL><p>
+</DL><p>"#);
+
+    set_caption(find_bookmark_entry(&mut items, "XÚÖ/DSCF4465.JPG").unwrap(), " Head unit straight on ");
+    assert_eq!(find_bookmark_entry(&mut items, "DSCF4465.JPG").unwrap().description.as_deref(), Some("Head unit straight on"));
+    assert!(find_bookmark_entry(&mut items, "nope.jpg").is_err());
+
+    // Keep the first, caption the second, clear the third, then stop
+    let mut input = io::Cursor::new("\nHead unit and old CDs\n:-\n:q\n");
+    let mut output = Vec::new();
+    let changed = caption_interactively(&mut items, Path::new("/nonexistent"), false, &mut input, &mut output).unwrap();
+    assert_eq!(changed, 2);
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("[2/4] DSCF4463.JPG\n  file: file missing\n  caption: (none)"));
+
+    let mut links = Vec::new();
+    collect_bookmark_links(&items, &mut links);
+    let captions: Vec<_> = links.iter().map(|e| e.description.as_deref()).collect();
+    assert_eq!(captions, [Some("Right speaker"), Some("Head unit and old CDs"), None, Some("Head unit straight on")]);
+
+    // Only the uncaptioned entry is offered, and a bare q is a caption
+    let mut output = Vec::new();
+    caption_interactively(&mut items, Path::new("/nonexistent"), true, &mut io::Cursor::new("q\n"), &mut output).unwrap();
+    assert!(String::from_utf8(output).unwrap().contains("[1/1] DSCF4464.JPG"));
+    assert_eq!(find_bookmark_entry(&mut items, "DSCF4464.JPG").unwrap().description.as_deref(), Some("q"));
+
+    // A file name shared by several entries needs the full href
+    let (_, mut items) = parse_bookmark_document(r#"<DL><p>
+    <DT><A HREF="2024/DSCF0001.JPG">DSCF0001.JPG</A>
+    <DT><A HREF="2025/DSCF0001.JPG">DSCF0001.JPG</A>
+    <DT><A HREF="DSCF0002.JPG">DSCF0002.JPG</A>
+    <DT><A HREF="2025/DSCF0002.JPG">DSCF0002.JPG</A>
+</DL><p>"#);
+    let err = find_bookmark_entry(&mut items, "DSCF0001.JPG").unwrap_err().to_string();
+    assert!(err.contains("2024/DSCF0001.JPG, 2025/DSCF0001.JPG"));
+    assert_eq!(find_bookmark_entry(&mut items, "2025/DSCF0001.JPG").unwrap().href, "2025/DSCF0001.JPG");
+    assert_eq!(find_bookmark_entry(&mut items, "DSCF0002.JPG").unwrap().href, "DSCF0002.JPG");
+}
 
-    // Scan directory for files and subdirectories
-    let (files, dirs) = scan_directory(folder, index_name, recursive)?;
+// Bookmark tag functionality
 
-    // Process subdirectories if recursive
-    let mut dir_contents = Vec::new();
-    if recursive {
-        for dir in &dirs {
-            let (dir_files, _) = scan_directory(dir, index_name, false)?;
-            dir_contents.push((dir.clone(), dir_files));
+// Both lists are cleaned up like the TAGS attribute, so " private" matches
+fn update_tags(tags: &mut Vec<String>, add: &[String], remove: &[String]) -> bool {
+    let before = tags.clone();
+    let remove = parse_tags(&remove.join(","));
+    tags.retain(|t| !remove.contains(t));
+    for tag in parse_tags(&add.join(",")) {
+        if !tags.contains(&tag) {
+            tags.push(tag);
         }
     }
+    *tags != before
+}
 
-    // Merge existing bookmarks with filesystem
-    let merged_items = merge_bookmarks(existing_items, &files, &dir_contents, folder)?;
+// What pixie and vault may publish: entries or notes with one of the
+// include tags (if any are given) and none of the exclude tags
+fn is_published(tags: &[String], include: &[String], exclude: &[String]) -> bool {
+    (include.is_empty() || tags.iter().any(|t| include.contains(t)))
+        && !tags.iter().any(|t| exclude.contains(t))
+}
 
-    // Generate HTML
-    let folder_name = folder
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("Bookmarks Menu");
-    let html = generate_bookmark_html(folder_name, &merged_items);
+fn filter_bookmark_items_by_tags(items: &[BookmarkItem], include: &[String], exclude: &[String]) -> Vec<BookmarkItem> {
+    items.iter()
+        .filter_map(|item| match item {
+            BookmarkItem::Link(entry) => {
+                is_published(&entry.tags, include, exclude).then(|| item.clone())
+            }
+            BookmarkItem::Folder(folder) => Some(BookmarkItem::Folder(BookmarkFolder {
+                entries: filter_bookmark_items_by_tags(&folder.entries, include, exclude),
+                ..folder.clone()
+            })),
+            BookmarkItem::Separator => Some(BookmarkItem::Separator),
+        })
+        .collect()
+}
 
-    // Write to file
-    let mut file = File::create(&index_path)?;
-    file.write_all(html.as_bytes())?;
+fn find_bookmarks_by_tags<'a>(items: &'a [BookmarkItem], tags: &[String]) -> Vec<&'a BookmarkEntry> {
+    let mut links = Vec::new();
+    collect_bookmark_links(items, &mut links);
+    links.retain(|entry| tags.iter().all(|tag| entry.tags.contains(tag)));
+    links
+}
 
-    println!("Bookmark index generated: {}", index_path.display());
-    println!("Total entries: {}", merged_items.len());
+fn handle_bookmarks_tag_command(
+    index: &Path,
+    files: &[String],
+    add: &[String],
+    remove: &[String],
+    backups: usize,
+) -> Result<(), Box<dyn Error>> {
+    let index_path = resolve_bookmark_index(index);
+    let content = fs::read_to_string(&index_path)
+        .map_err(|e| format!("Cannot read {}: {}", index_path.display(), e))?;
+    let (title, mut items) = parse_bookmark_document(&content);
+
+    let mut changed = 0;
+    for file in files {
+        let entry = find_bookmark_entry(&mut items, file)?;
+        if add.is_empty() && remove.is_empty() {
+            println!("{}\t{}", file, entry.tags.join(","));
+        } else if update_tags(&mut entry.tags, add, remove) {
+            changed += 1;
+        }
+    }
+
+    if changed > 0 {
+        write_bookmark_index(&index_path, &generate_bookmark_html(&title, &items), backups)?;
+        println!("Bookmark index updated: {} ({} entries retagged)", index_path.display(), changed);
+    }
+
+    Ok(())
+}
+
+fn handle_bookmarks_find_command(index: &Path, tags: &[String]) -> Result<(), Box<dyn Error>> {
+    let index_path = resolve_bookmark_index(index);
+    let content = fs::read_to_string(&index_path)
+        .map_err(|e| format!("Cannot read {}: {}", index_path.display(), e))?;
+    let items = parse_existing_bookmarks(&content);
+
+    // Local files print as paths, so the output can feed other tools
+    for entry in find_bookmarks_by_tags(&items, tags) {
+        match urlencoding::decode(&entry.href) {
+            Ok(path) if local_bookmark_path(&entry.href, Path::new("")).is_some() => println!("{}", path),
+            _ => println!("{}", entry.href),
+        }
+    }
 
     Ok(())
 }
 
+#[test]
+fn test_bookmark_tags() {
+    let (_, mut items) = parse_bookmark_document(r#"<DL><p>
+    <DT><A HREF="DSCF4462.JPG" TAGS="favorite, portfolio,favorite">DSCF4462.JPG</A>
+    <DT><A HREF="DSCF4463.JPG" TAGS="private">DSCF4463.JPG</A>
+    <DT><H3>XÚÖ</H3>
+    <DL><p>
+        <DT><A HREF="X%C3%9A%C3%96/DSCF4464.JPG">DSCF4464.JPG</A>
+    </DL><p>
+</DL><p>"#);
+
+    let entry = find_bookmark_entry(&mut items, "DSCF4464.JPG").unwrap();
+    assert!(update_tags(&mut entry.tags, &["favorite".to_string()], &[]));
+    assert!(!update_tags(&mut entry.tags, &["favorite".to_string()], &["portfolio".to_string()]));
+    assert!(update_tags(&mut entry.tags, &[" private ".to_string(), String::new()], &[]));
+    assert!(update_tags(&mut entry.tags, &[], &[" private".to_string()]));
+    assert_eq!(entry.tags, ["favorite"]);
+
+    let found: Vec<_> = find_bookmarks_by_tags(&items, &["favorite".to_string()]).iter().map(|e| e.href.clone()).collect();
+    assert_eq!(found, ["DSCF4462.JPG", "X%C3%9A%C3%96/DSCF4464.JPG"]);
+    assert_eq!(find_bookmarks_by_tags(&items, &["favorite".to_string(), "portfolio".to_string()]).len(), 1);
+
+    // Tags are written as the standard TAGS attribute
+    let html = generate_bookmark_html("XÚÖ", &items);
+    assert!(html.contains(r#"<A HREF="DSCF4462.JPG" ADD_DATE="0" LAST_MODIFIED="0" TAGS="favorite,portfolio">"#));
+
+    let private = ["private".to_string()];
+    let published = extract_bookmark_files(&filter_bookmark_items_by_tags(&items, &[], &private));
+    assert_eq!(published.iter().map(|f| f.href.as_str()).collect::<Vec<_>>(), ["DSCF4462.JPG", "X%C3%9A%C3%96/DSCF4464.JPG"]);
+    assert_eq!(published[0].tags, ["favorite", "portfolio"]);
+    let portfolio = extract_bookmark_files(&filter_bookmark_items_by_tags(&items, &["portfolio".to_string()], &private));
+    assert_eq!(portfolio.len(), 1);
+
+    let (metadata, _) = parse_frontmatter("---\npublish: true\ntags: [notes, \"#private\"]\n---\nBody").unwrap();
+    assert_eq!(frontmatter_tags(&metadata), ["notes", "private"]);
+    assert!(!is_published(&frontmatter_tags(&metadata), &[], &default_exclude_tags()));
+    assert!(is_published(&frontmatter_tags(&metadata), &["notes".to_string()], &[]));
+    let (metadata, _) = parse_frontmatter("---\ntags: travel, photos\n---\nBody").unwrap();
+    assert_eq!(frontmatter_tags(&metadata), ["travel", "photos"]);
+}
+
 // Pixie functionality
 
 fn log_command(cmd: &Command) {
@@ -760,15 +5497,13 @@ fn find_album_folders(
         if max_depth.is_none() || current_depth < max_depth.unwrap() {
             // Read subdirectories
             if let Ok(entries) = fs::read_dir(&current_path) {
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        let path = entry.path();
-                        if path.is_dir() {
-                            // Skip hidden directories
-                            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                                if !name.starts_with('.') {
-                                    queue.push_back((path, current_depth + 1));
-                                }
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    if path.is_dir() {
+                        // Skip hidden directories
+                        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                            if !name.starts_with('.') {
+                                queue.push_back((path, current_depth + 1));
                             }
                         }
                     }
@@ -790,12 +5525,14 @@ fn extract_bookmark_files(items: &[BookmarkItem]) -> Vec<BookmarkFile> {
                     href: entry.href.clone(),
                     name: entry.name.clone(),
                     caption: entry.description.clone(),
+                    tags: entry.tags.clone(),
                 });
             }
             BookmarkItem::Folder(folder) => {
                 // Recursively extract from folder
                 files.extend(extract_bookmark_files(&folder.entries));
             }
+            BookmarkItem::Separator => {}
         }
     }
 
@@ -825,11 +5562,11 @@ fn run_imagemagick_resize(
     cmd.arg(filename)  // Use just the filename since current_dir is set
         .arg("-auto-orient")  // Rotate pixels to match EXIF, strip orientation tag
         .arg("-resize")
-        .arg(&resize_spec)
+        .arg(resize_spec)
         .arg("-set")
         .arg("filename:f")
         .arg(&filename_pattern)
-        .arg(&output_pattern)
+        .arg(output_pattern)
         .current_dir(file_dir);  // Set working directory to output folder
 
     log_command(&cmd);
@@ -910,6 +5647,36 @@ fn copy_and_resize_files(
     Ok(())
 }
 
+// The copies and resized versions of files left out by tag, from an earlier
+// run. Returns the number of files removed
+fn remove_unpublished_files(
+    all_files: &[BookmarkFile],
+    published: &[BookmarkFile],
+    dest_folder: &Path,
+    resize_args: &HashMap<String, String>,
+) -> Result<usize, Box<dyn Error>> {
+    // Output is flattened, so only names no published file uses can go
+    let output_name = |file: &BookmarkFile| href_file_name(&file.href);
+    let published_names: HashSet<String> = published.iter().map(output_name).collect();
+
+    let mut removed = 0;
+    for name in all_files.iter().map(output_name).filter(|n| !published_names.contains(n)) {
+        let path = Path::new(&name);
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(&name);
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let resized = resize_args.keys().map(|suffix| format!("{}.{}.{}", stem, suffix, extension));
+
+        for output in std::iter::once(name.clone()).chain(resized) {
+            let output_path = dest_folder.join(output);
+            if output_path.exists() {
+                fs::remove_file(&output_path)?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
 fn get_image_dimensions(folder: &Path) -> Result<HashMap<String, (u32, u32)>, Box<dyn Error>> {
     // Run: magick identify -format "%f,%w,%h\n" folder/*
     let pattern = folder.join("*");
@@ -934,7 +5701,7 @@ fn get_image_dimensions(folder: &Path) -> Result<HashMap<String, (u32, u32)>, Bo
         let parts: Vec<&str> = line.split(',').collect();
         if parts.len() == 3 {
             if let (Some(filename), Some(width), Some(height)) = (
-                parts.get(0),
+                parts.first(),
                 parts.get(1).and_then(|s| s.parse::<u32>().ok()),
                 parts.get(2).and_then(|s| s.parse::<u32>().ok()),
             ) {
@@ -995,7 +5762,7 @@ fn build_album_index(
 
         // Build sizes map
         let mut sizes = HashMap::new();
-        for (suffix, _) in resize_args {
+        for suffix in resize_args.keys() {
             let resized_filename = format!(
                 "{}.{}.{}",
                 Path::new(&filename).file_stem()
@@ -1023,6 +5790,7 @@ fn build_album_index(
             w,
             h,
             caption: file.caption.clone(),
+            tags: file.tags.clone(),
             sizes,
         });
     }
@@ -1042,28 +5810,50 @@ fn process_album(
     // Extract title from H1
     let title = extract_title_from_bookmarks(&index_content);
 
-    let bookmark_items = parse_existing_bookmarks(&index_content);
+    let all_items = parse_existing_bookmarks(&index_content);
+    let bookmark_items = filter_bookmark_items_by_tags(&all_items, &config.include_tags, &config.exclude_tags);
 
     // Extract all file hrefs with captions
     let bookmark_files = extract_bookmark_files(&bookmark_items);
+    let all_files = extract_bookmark_files(&all_items);
+    let skipped = all_files.len() - bookmark_files.len();
     println!("  Found {} files in bookmarks", bookmark_files.len());
-
-    if bookmark_files.is_empty() {
-        println!("  Skipping: no files to process");
-        return Ok(());
+    if skipped > 0 {
+        println!("  Left out {} files by tag", skipped);
     }
 
     // Create output folder using just the album name
     let output_folder_path = expand_tilde_path(&config.output_folder)?;
     let output_album_path = output_folder_path.join(&album.album_name);
 
+    if bookmark_files.is_empty() {
+        // Everything may have been tagged private since the last run
+        if output_album_path.exists() {
+            fs::remove_dir_all(&output_album_path)?;
+            println!("  Removed previous output: {}", output_album_path.display());
+        }
+        println!("  Skipping: no files to process");
+        return Ok(());
+    }
+
     // Copy files and create resized versions
     copy_and_resize_files(&bookmark_files, &album.path, &output_album_path, &config.resize_args)?;
+    if skipped > 0 {
+        let removed = remove_unpublished_files(&all_files, &bookmark_files, &output_album_path, &config.resize_args)?;
+        if removed > 0 {
+            println!("  Removed {} previously published files left out by tag", removed);
+        }
+    }
 
-    // Copy index file to output
+    // Copy index file to output, without the entries left out by tag
     let output_index_path = output_album_path.join(&config.index_file_name);
-    fs::copy(&album.index_path, &output_index_path)?;
-    println!("  Copied index file");
+    if skipped == 0 {
+        fs::copy(&album.index_path, &output_index_path)?;
+        println!("  Copied index file");
+    } else {
+        fs::write(&output_index_path, generate_bookmark_html(&title, &bookmark_items))?;
+        println!("  Wrote filtered index file");
+    }
 
     // Get image dimensions for all files in the output folder
     println!("  Getting image dimensions...");
@@ -1087,7 +5877,7 @@ fn process_album(
     Ok(())
 }
 
-fn handle_pixie_command(config_path: &PathBuf) -> Result<(), Box<dyn Error>> {
+fn handle_pixie_command(config_path: &Path) -> Result<(), Box<dyn Error>> {
     // Check if ImageMagick is available
     let mut version_cmd = Command::new("magick");
     version_cmd.arg("--version");
@@ -1157,6 +5947,23 @@ fn handle_pixie_command(config_path: &PathBuf) -> Result<(), Box<dyn Error>> {
 
 // Vault functionality
 
+// Obsidian accepts `tags` or `tag`, as a list or a comma/space separated
+// string, with or without a leading '#'
+fn frontmatter_tags(metadata: &HashMap<String, serde_yaml::Value>) -> Vec<String> {
+    let Some(value) = metadata.get("tags").or_else(|| metadata.get("tag")) else {
+        return Vec::new();
+    };
+    let raw: Vec<String> = match value {
+        serde_yaml::Value::Sequence(items) => items.iter().filter_map(|v| v.as_str()).map(str::to_string).collect(),
+        serde_yaml::Value::String(text) => text.split([',', ' ']).map(str::to_string).collect(),
+        _ => Vec::new(),
+    };
+    raw.iter()
+        .map(|t| t.trim().trim_start_matches('#').to_string())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
 fn parse_frontmatter(content: &str) -> Result<(HashMap<String, serde_yaml::Value>, String), Box<dyn Error>> {
     // Check if content starts with frontmatter delimiter
     if !content.starts_with("---\n") && !content.starts_with("---\r\n") {
@@ -1164,11 +5971,8 @@ fn parse_frontmatter(content: &str) -> Result<(HashMap<String, serde_yaml::Value
     }
 
     // Find the closing delimiter
-    let after_first = if content.starts_with("---\r\n") {
-        &content[5..]
-    } else {
-        &content[4..]
-    };
+    let after_first = content.strip_prefix("---\r\n")
+        .unwrap_or(&content[4..]);
 
     if let Some(end_pos) = after_first.find("\n---\n").or_else(|| after_first.find("\n---\r\n")) {
         let yaml_section = &after_first[..end_pos];
@@ -1243,21 +6047,33 @@ fn convert_wikilinks(content: &str, index: &HashMap<String, PathBuf>) -> String
     }).to_string()
 }
 
+struct VaultOptions {
+    include_tags: Vec<String>,
+    exclude_tags: Vec<String>,
+}
+
 fn process_vault_file(
     input_path: &Path,
     output_path: &Path,
-    index: &HashMap<String, PathBuf>
-) -> Result<(), Box<dyn Error>> {
+    index: &HashMap<String, PathBuf>,
+    options: &VaultOptions,
+) -> Result<bool, Box<dyn Error>> {
     let content = fs::read_to_string(input_path)?;
     let (metadata, body) = parse_frontmatter(&content)?;
 
-    // Check if file should be published
+    // Check if file should be published; tag filters apply on top of the
+    // publish flag
     let should_publish = metadata.get("publish")
         .and_then(|v| v.as_bool())
-        .unwrap_or(false);
+        .unwrap_or(false)
+        && is_published(&frontmatter_tags(&metadata), &options.include_tags, &options.exclude_tags);
 
+    // A note that was published before mustn't stay out there
     if !should_publish {
-        return Ok(());
+        if output_path.exists() {
+            fs::remove_file(output_path)?;
+        }
+        return Ok(false);
     }
 
     // Convert wikilinks in body
@@ -1289,10 +6105,10 @@ fn process_vault_file(
     }
     fs::write(output_path, output)?;
 
-    Ok(())
+    Ok(true)
 }
 
-fn handle_vault_command(vault_dir: &PathBuf, output_dir: &PathBuf) -> Result<(), Box<dyn Error>> {
+fn handle_vault_command(vault_dir: &Path, output_dir: &Path, options: &VaultOptions) -> Result<(), Box<dyn Error>> {
     // Expand tilde in paths
     let vault_dir = expand_tilde_path(&vault_dir.to_string_lossy())?;
     let output_dir = expand_tilde_path(&output_dir.to_string_lossy())?;
@@ -1323,6 +6139,7 @@ fn handle_vault_command(vault_dir: &PathBuf, output_dir: &PathBuf) -> Result<(),
         vault_root: &Path,
         output_root: &Path,
         index: &HashMap<String, PathBuf>,
+        options: &VaultOptions,
         processed: &mut usize,
         skipped: &mut usize
     ) -> Result<(), Box<dyn Error>> {
@@ -1331,21 +6148,16 @@ fn handle_vault_command(vault_dir: &PathBuf, output_dir: &PathBuf) -> Result<(),
             let path = entry.path();
 
             if path.is_dir() {
-                walk_and_process(&path, vault_root, output_root, index, processed, skipped)?;
+                walk_and_process(&path, vault_root, output_root, index, options, processed, skipped)?;
             } else if path.is_file() {
                 if let Some(ext) = path.extension() {
                     if ext == "md" {
                         let rel_path = path.strip_prefix(vault_root).unwrap_or(&path);
                         let output_path = output_root.join(rel_path);
 
-                        match process_vault_file(&path, &output_path, index) {
-                            Ok(_) => {
-                                if output_path.exists() {
-                                    *processed += 1;
-                                } else {
-                                    *skipped += 1;
-                                }
-                            }
+                        match process_vault_file(&path, &output_path, index, options) {
+                            Ok(true) => *processed += 1,
+                            Ok(false) => *skipped += 1,
                             Err(e) => {
                                 eprintln!("Warning: Failed to process {}: {}", path.display(), e);
                                 *skipped += 1;
@@ -1358,7 +6170,7 @@ fn handle_vault_command(vault_dir: &PathBuf, output_dir: &PathBuf) -> Result<(),
         Ok(())
     }
 
-    walk_and_process(&vault_dir, &vault_dir, &output_dir, &index, &mut processed_count, &mut skipped_count)?;
+    walk_and_process(&vault_dir, &vault_dir, &output_dir, &index, options, &mut processed_count, &mut skipped_count)?;
 
     println!("\nDone!");
     println!("Processed: {}", processed_count);
@@ -1368,17 +6180,113 @@ fn handle_vault_command(vault_dir: &PathBuf, output_dir: &PathBuf) -> Result<(),
     Ok(())
 }
 
+#[test]
+fn test_remove_unpublished_output() {
+    let tmp = TestDir::new("unpublished");
+    let dir = tmp.path();
+
+    // Pixie: DSCF4463 was published, then tagged private
+    let (_, items) = parse_bookmark_document(r#"<DL><p>
+    <DT><A HREF="DSCF4462.JPG">DSCF4462.JPG</A>
+    <DT><A HREF="raw/DSCF4463.JPG" TAGS="private">DSCF4463.JPG</A>
+</DL><p>"#);
+    for name in ["DSCF4462.JPG", "DSCF4462.rs.JPG", "DSCF4463.JPG", "DSCF4463.rs.JPG"] {
+        fs::write(dir.join(name), "").unwrap();
+    }
+    let all_files = extract_bookmark_files(&items);
+    let published = extract_bookmark_files(&filter_bookmark_items_by_tags(&items, &[], &default_exclude_tags()));
+    let resize_args = HashMap::from([("rs".to_string(), "800x800>".to_string())]);
+    assert_eq!(remove_unpublished_files(&all_files, &published, dir, &resize_args).unwrap(), 2);
+    assert!(dir.join("DSCF4462.rs.JPG").exists());
+    assert!(!dir.join("DSCF4463.JPG").exists());
+    assert!(!dir.join("DSCF4463.rs.JPG").exists());
+
+    // Vault: a note tagged private loses its earlier output
+    let (note, output) = (dir.join("note.md"), dir.join("out/note.md"));
+    let options = VaultOptions { include_tags: Vec::new(), exclude_tags: default_exclude_tags() };
+    fs::write(&note, "---\npublish: true\n---\nBody").unwrap();
+    assert!(process_vault_file(&note, &output, &HashMap::new(), &options).unwrap());
+    assert!(output.exists());
+    fs::write(&note, "---\npublish: true\ntags: [private]\n---\nBody").unwrap();
+    assert!(!process_vault_file(&note, &output, &HashMap::new(), &options).unwrap());
+    assert!(!output.exists());
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Tree { file } => handle_tree_command(file)?,
-        Commands::Bookmarks { folder, index, recursive } => {
-            handle_bookmarks_command(folder, index, *recursive)?
+        Commands::Tree { action, file, format, output, summary, filter } => match action {
+            Some(TreeCommands::Diff { old, new, format }) => {
+                handle_tree_diff_command(old, new, *format)?
+            }
+            Some(TreeCommands::Scan { path, output, timefmt, all }) => {
+                handle_tree_scan_command(path, output.as_deref(), timefmt, *all)?
+            }
+            Some(TreeCommands::Du { file, top }) => handle_tree_du_command(file, *top)?,
+            Some(TreeCommands::Dupes { files, hash, min_size }) => {
+                handle_tree_dupes_command(files, *hash, *min_size)?
+            }
+            Some(TreeCommands::Html { file, output }) => {
+                handle_tree_html_command(file, output.as_deref())?
+            }
+            Some(TreeCommands::Bookmarks { file, subtree, output }) => {
+                handle_tree_bookmarks_command(file, subtree.as_deref(), output.as_deref())?
+            }
+            None => {
+                let file = file.as_deref().ok_or("FILE is required")?;
+                handle_tree_command(file, *format, output.as_deref(), *summary, filter)?
+            }
+        },
+        Commands::Bookmarks { action: Some(BookmarksCommands::Export { index, format, output }), .. } => {
+            handle_bookmarks_export_command(index, *format, output.as_deref())?
+        }
+        Commands::Bookmarks { action: Some(BookmarksCommands::Import { file, into, backups }), .. } => {
+            handle_bookmarks_import_command(file, into, *backups)?
+        }
+        Commands::Bookmarks { action: Some(BookmarksCommands::Restore { index, backup, list, backups }), .. } => {
+            handle_bookmarks_restore_command(index, *backup, *list, *backups)?
+        }
+        Commands::Bookmarks {
+            action: Some(BookmarksCommands::Caption { index, file, text, missing, backups }), ..
+        } => handle_bookmarks_caption_command(index, file.as_deref(), text.as_deref(), *missing, *backups)?,
+        Commands::Bookmarks { action: Some(BookmarksCommands::Tag { index, files, add, remove, backups }), .. } => {
+            handle_bookmarks_tag_command(index, files, add, remove, *backups)?
+        }
+        Commands::Bookmarks { action: Some(BookmarksCommands::Find { index, tag }), .. } => {
+            handle_bookmarks_find_command(index, tag)?
+        }
+        Commands::Bookmarks {
+            action: None, folder, index, recursive, max_depth, include, exclude, sync, sort,
+            preserve_order, image_metadata, watch, dry_run, diff, backups,
+        } => {
+            let folder = folder.as_deref().ok_or("FOLDER is required")?;
+            let options = BookmarkMergeOptions {
+                sync: *sync,
+                sort: *sort,
+                preserve_order: *preserve_order,
+                image_metadata: *image_metadata,
+            };
+            let filter = BookmarkScanFilter {
+                include: include.clone(),
+                exclude: exclude.clone(),
+            };
+            let write = BookmarkWriteOptions {
+                dry_run: *dry_run,
+                diff: *diff,
+                backups: *backups,
+            };
+            handle_bookmarks_command(folder, index, *recursive, *max_depth, &filter, &options, &write)?;
+            if *watch {
+                watch_bookmarks(folder, index, *recursive, || {
+                    handle_bookmarks_command(folder, index, *recursive, *max_depth, &filter, &options, &write)
+                })?
+            }
         }
         Commands::Pixie { config } => handle_pixie_command(config)?,
-        Commands::Vault { vault_dir, output_dir } => {
-            handle_vault_command(vault_dir, output_dir)?
+        Commands::Vault { vault_dir, output_dir, include_tags, exclude_tags } => {
+            let options = VaultOptions { include_tags: include_tags.clone(), exclude_tags: exclude_tags.clone() };
+            handle_vault_command(vault_dir, output_dir, &options)?
         }
     }
 